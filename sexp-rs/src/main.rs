@@ -1,31 +1,58 @@
 //! A single–file evaluator for our DSL.
 //!
 //! This program parses S–expressions that track line numbers and supports DSL forms:
-//!   base-cmd, load-env, load-config, types, def, task, and group.
+//!   base-cmd, load-env, load-config, types, def, default, task, and group.
 //!
-//! It implements built–in functions (or, and, if, equal?, env, conf, git-root,
-//! current-timestamp, shell, from-shell) and performs string interpolation
-//! (using {var} syntax with a maximum recursion depth of 10).
+//! It implements built–in functions (or, and, if, when, unless, let, equal?, equal-ci?,
+//! contains?, starts-with?, ends-with?, first, nth, length, join, echo, upper, lower,
+//! split, replace, map, env, conf, git-root, current-timestamp, current-date, glob,
+//! uuid, random, read-file, json-get, shell, shell-nocache, shell-args, from-shell,
+//! from-shell-lines) and performs string interpolation (using {var} syntax with a
+//! default maximum recursion depth of 10, configurable with `--max-interp-depth`).
 //!
 //! The CLI supports:
-//!   - Listing tasks: `dsl --list` (with optional `--verbose` for descriptions)
+//!   - Listing tasks: `dsl --list` (with optional `--verbose` for descriptions, or
+//!     `--json` for a machine-readable array)
+//!   - Hiding the task status with `--quiet`, or showing the variables with `--verbose`
+//!   - Printing per-task durations and the total run time with `--timings`
+//!   - Tracing every function call and its result during evaluation with `--trace`
+//!   - Warning about defs that are never referenced with `--warn-unused`
+//!   - Echoing the output of tasks that write a `log` file with `--tee`
+//!   - Making the `random` builtin reproducible with `--seed`
+//!   - Skipping tasks that were already done with `--skip`, or running a single task
+//!     without its steps with `--only`
+//!   - Exporting the task dependency graph in Graphviz dot format with `--graph`
+//!   - Validating the file without running anything with `--check`
 //!   - Running tasks (or groups), e.g. `dsl eval.accuracy` or `dsl train eval.accuracy`
 //!   - Passing extra arguments: e.g. `dsl eval.accuracy -- --verbose`
 //!   - Specifying the DSL file with `--file`/`-f` (default: "tasks.dsl")
-//!   - When no tasks are provided, it defaults to the "default" task.
+//!   - Keeping `load-env` variables out of the process environment with `--isolated-env`
+//!   - Coloring the status output (printed to stderr) with `--color {auto,always,never}`
+//!   - When no tasks are provided, it asks which task to run if attached to a terminal
+//!     (unless `--no-interactive`). Otherwise, it runs the task named by a
+//!     `(default ...)` form, or else the "default" task.
 //!   - Every evaluation error is annotated with the line number where it occurred.
 
+use chrono::format::{Item, StrftimeItems};
 use chrono::Utc;
-use clap::Parser as ClapParser;
+use clap::{Parser as ClapParser, ValueEnum};
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
 use regex::Regex;
 use serde_json::Value as JsonValue;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::fmt;
 use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::Path;
-use std::process::Command;
+use std::process::{self, Command, ExitStatus, Output, Stdio};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 // ======================================================================
@@ -55,10 +82,72 @@ struct Cli {
     #[arg(long)]
     list: bool,
 
-    /// Print descriptions with the task list
-    #[arg(long)]
+    /// With `--list`, print the tasks as a JSON array
+    #[arg(long, requires = "list")]
+    json: bool,
+
+    /// Validate the DSL file and every task command without running anything
+    #[arg(long, conflicts_with_all = ["list", "graph"])]
+    check: bool,
+
+    /// Print the task dependency graph in Graphviz dot format instead of running
+    #[arg(long, conflicts_with = "list")]
+    graph: bool,
+
+    /// Print descriptions with the task list, and the interpolation variables
+    /// when running tasks
+    #[arg(long, conflicts_with = "quiet")]
     verbose: bool,
 
+    /// Only print the output of the commands, not the task status
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Keep variables from `load-env` out of the process environment and pass
+    /// them only to task commands
+    #[arg(long)]
+    isolated_env: bool,
+
+    /// Print how long each task took and a summary at the end
+    #[arg(long)]
+    timings: bool,
+
+    /// Log every function call and its result to stderr while evaluating
+    #[arg(long)]
+    trace: bool,
+
+    /// Warn about defs that are never referenced
+    #[arg(long)]
+    warn_unused: bool,
+
+    /// Also print the output of tasks with a `log` file to the terminal
+    #[arg(long)]
+    tee: bool,
+
+    /// Treat a task as already done, so it is not run as a step (repeatable)
+    #[arg(long, value_name = "TASK")]
+    skip: Vec<String>,
+
+    /// Run only the command of a task, without its steps or hooks (repeatable)
+    #[arg(long, value_name = "TASK", conflicts_with = "tasks")]
+    only: Vec<String>,
+
+    /// Never prompt for a task; with no tasks given, run the default one
+    #[arg(long)]
+    no_interactive: bool,
+
+    /// Seed for the `random` builtin, to make runs reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// When to color the status output
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Maximum number of nested interpolation passes
+    #[arg(long, default_value_t = 10)]
+    max_interp_depth: usize,
+
     /// Names of tasks or groups to run
     #[arg()]
     tasks: Vec<String>,
@@ -68,6 +157,45 @@ struct Cli {
     extra_args: Vec<String>,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    /// Color if stderr is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => env::var_os("NO_COLOR").is_none() && io::stderr().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    Red,
+    Green,
+    Cyan,
+    Bold,
+    Reset,
+}
+
+impl Color {
+    fn code(&self) -> &str {
+        match self {
+            Color::Red => "\x1b[31m",
+            Color::Green => "\x1b[32m",
+            Color::Cyan => "\x1b[36m",
+            Color::Bold => "\x1b[1m",
+            Color::Reset => "\x1b[0m",
+        }
+    }
+}
+
 // ======================================================================
 // S–Expression parser with location tracking
 // ======================================================================
@@ -76,8 +204,13 @@ struct Cli {
 pub enum SExp {
     Symbol(String, usize),
     String(String, usize),
+    Number(Number, usize),
     List(Vec<SExp>, usize),
     Quoted(Box<SExp>, usize),
+    /// Only produced by a parser that captures comments. It sits in its list (or
+    /// among the top-level forms) right before the node it describes, or right
+    /// after the node it trails.
+    Comment(Comment, usize),
 }
 
 impl SExp {
@@ -86,8 +219,10 @@ impl SExp {
         match self {
             SExp::Symbol(_, line) => *line,
             SExp::String(_, line) => *line,
+            SExp::Number(_, line) => *line,
             SExp::List(_, line) => *line,
             SExp::Quoted(_, line) => *line,
+            SExp::Comment(_, line) => *line,
         }
     }
 }
@@ -117,7 +252,8 @@ impl fmt::Display for SExp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             SExp::Symbol(s, _) => write!(f, "{}", s),
-            SExp::String(s, _) => write!(f, "\"{}\"", s),
+            SExp::String(s, _) => write!(f, "{}", quote_string(s)),
+            SExp::Number(n, _) => write!(f, "{}", format_num(*n)),
             SExp::List(items, _) => {
                 write!(f, "(")?;
                 for (i, item) in items.iter().enumerate() {
@@ -129,8 +265,34 @@ impl fmt::Display for SExp {
                 write!(f, ")")
             }
             SExp::Quoted(exp, _) => write!(f, "'{}", exp),
+            SExp::Comment(comment, _) => write!(f, "{}", comment.text),
+        }
+    }
+}
+
+/// Write a string literal that parses back to `s`.
+fn quote_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
+}
+
+/// A `;` comment kept by a comment-capturing parser.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub text: String,   // Including the leading comment character.
+    pub trailing: bool, // Follows code on the same line.
 }
 
 pub struct Parser<'a> {
@@ -140,6 +302,7 @@ pub struct Parser<'a> {
     true_val: &'a str,
     false_val: Option<&'a str>,
     line_comment: char,
+    capture_comments: bool,
 }
 
 impl<'a> Parser<'a> {
@@ -157,9 +320,16 @@ impl<'a> Parser<'a> {
             true_val,
             false_val,
             line_comment,
+            capture_comments: false,
         }
     }
 
+    /// Keep comments instead of discarding them, so they can be written back.
+    pub fn capture_comments(mut self) -> Self {
+        self.capture_comments = true;
+        self
+    }
+
     /// Compute the current line number (starting at 1).
     fn current_line(&self) -> usize {
         self.text[..self.pos].matches('\n').count() + 1
@@ -167,16 +337,7 @@ impl<'a> Parser<'a> {
 
     fn parse_sexp(&mut self) -> Result<SExp, ParseError> {
         let chars: Vec<char> = self.text.chars().collect();
-        while self.pos < chars.len() {
-            let c = chars[self.pos];
-            if c.is_whitespace() {
-                self.pos += 1;
-            } else if c == self.line_comment {
-                self.skip_comment(&chars);
-            } else {
-                break;
-            }
-        }
+        self.skip_whitespace_and_comments(&chars);
         if self.pos >= chars.len() {
             return Err(ParseError::UnexpectedEOF(self.current_line()));
         }
@@ -185,7 +346,13 @@ impl<'a> Parser<'a> {
                 let start_line = self.current_line();
                 self.pos += 1;
                 let mut list = Vec::new();
-                while self.pos < chars.len() && chars[self.pos] != ')' {
+                loop {
+                    // Skip up to the next element here, so whitespace and comments
+                    // before the closing paren are allowed.
+                    list.extend(self.skip_whitespace_and_comments(&chars));
+                    if self.pos >= chars.len() || chars[self.pos] == ')' {
+                        break;
+                    }
                     list.push(self.parse_sexp()?);
                 }
                 if self.pos >= chars.len() {
@@ -212,6 +379,7 @@ impl<'a> Parser<'a> {
                 let start_line = self.current_line();
                 self.parse_atom(&chars).map(|s| match s {
                     SExp::Symbol(val, _) => SExp::Symbol(val, start_line),
+                    SExp::Number(n, _) => SExp::Number(n, start_line),
                     other => other,
                 })
             }
@@ -265,41 +433,82 @@ impl<'a> Parser<'a> {
             s if Some(s) == self.false_val => {
                 SExp::Symbol("false".to_string(), self.current_line())
             }
-            _ => {
-                if let Ok(n) = token.parse::<i64>() {
-                    SExp::Symbol(n.to_string(), self.current_line())
-                } else if let Ok(f) = token.parse::<f64>() {
-                    SExp::Symbol(f.to_string(), self.current_line())
-                } else {
-                    SExp::Symbol(token, self.current_line())
-                }
-            }
+            _ => match Number::parse(&token) {
+                Some(n) => SExp::Number(n, self.current_line()),
+                None => SExp::Symbol(token, self.current_line()),
+            },
         })
     }
 
-    fn skip_comment(&mut self, chars: &[char]) {
+    /// Skip a comment, returning it as a node if comments are being captured.
+    fn skip_comment(&mut self, chars: &[char]) -> Option<SExp> {
+        let start = self.pos;
         while self.pos < chars.len() && chars[self.pos] != '\n' {
             self.pos += 1;
         }
+        if !self.capture_comments {
+            return None;
+        }
+        let trailing = chars[..start]
+            .iter()
+            .rev()
+            .take_while(|&&c| c != '\n')
+            .any(|c| !c.is_whitespace());
+        let comment = Comment {
+            text: chars[start..self.pos]
+                .iter()
+                .collect::<String>()
+                .trim_end()
+                .to_string(),
+            trailing,
+        };
+        Some(SExp::Comment(comment, self.current_line()))
+    }
+
+    /// Skip whitespace and comments, returning the comments that were captured.
+    fn skip_whitespace_and_comments(&mut self, chars: &[char]) -> Vec<SExp> {
+        let mut comments = Vec::new();
+        while self.pos < chars.len() {
+            let c = chars[self.pos];
+            if c.is_whitespace() {
+                self.pos += 1;
+            } else if c == self.line_comment {
+                comments.extend(self.skip_comment(chars));
+            } else {
+                break;
+            }
+        }
+        comments
+    }
+
+    /// Move past `start` to the next `(` at the beginning of a line, which is
+    /// assumed to open a new top-level form.
+    fn resync_to_next_form(&mut self, chars: &[char], start: usize) {
+        self.pos = start + 1;
+        while self.pos < chars.len() && !(chars[self.pos] == '(' && chars[self.pos - 1] == '\n') {
+            self.pos += 1;
+        }
     }
 }
 
 /// Parse all top-level forms from the input string.
 pub fn loads_all(s: &str) -> Result<Vec<SExp>, ParseError> {
-    let mut forms = Vec::new();
     let mut parser = Parser::new(s, "nil", "t", None, ';');
+    parse_forms(&mut parser, s)
+}
+
+/// Parse all top-level forms, keeping comments as `SExp::Comment` nodes so they
+/// can be written back with `dumps_pretty_all`.
+pub fn loads_all_with_comments(s: &str) -> Result<Vec<SExp>, ParseError> {
+    let mut parser = Parser::new(s, "nil", "t", None, ';').capture_comments();
+    parse_forms(&mut parser, s)
+}
+
+fn parse_forms(parser: &mut Parser, s: &str) -> Result<Vec<SExp>, ParseError> {
+    let mut forms = Vec::new();
     let chars: Vec<char> = s.chars().collect();
     while parser.pos < chars.len() {
-        while parser.pos < chars.len() {
-            let c = chars[parser.pos];
-            if c.is_whitespace() {
-                parser.pos += 1;
-            } else if c == parser.line_comment {
-                parser.skip_comment(&chars);
-            } else {
-                break;
-            }
-        }
+        forms.extend(parser.skip_whitespace_and_comments(&chars));
         if parser.pos >= chars.len() {
             break;
         }
@@ -315,6 +524,44 @@ pub fn loads_all(s: &str) -> Result<Vec<SExp>, ParseError> {
     Ok(forms)
 }
 
+/// Parse all top-level forms, collecting every parse error instead of stopping
+/// at the first one.
+///
+/// After an error, parsing resumes at the next `(` at the start of a line.
+pub fn loads_all_collect(s: &str) -> Result<Vec<SExp>, Vec<ParseError>> {
+    let mut forms = Vec::new();
+    let mut errors = Vec::new();
+    let mut parser = Parser::new(s, "nil", "t", None, ';');
+    let chars: Vec<char> = s.chars().collect();
+    while parser.pos < chars.len() {
+        parser.skip_whitespace_and_comments(&chars);
+        if parser.pos >= chars.len() {
+            break;
+        }
+        let start = parser.pos;
+        let result = if chars[start] != '(' {
+            Err(ParseError::UnexpectedContent(
+                format!("Expected '(' at position {}", start),
+                parser.current_line(),
+            ))
+        } else {
+            parser.parse_sexp()
+        };
+        match result {
+            Ok(form) => forms.push(form),
+            Err(e) => {
+                errors.push(e);
+                parser.resync_to_next_form(&chars, start);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(forms)
+    } else {
+        Err(errors)
+    }
+}
+
 // ======================================================================
 // DSL Evaluator definitions and context
 // ======================================================================
@@ -322,20 +569,81 @@ pub fn loads_all(s: &str) -> Result<Vec<SExp>, ParseError> {
 #[derive(Debug, Clone)]
 enum Value {
     Str(String),
+    Num(Number),
     List(Vec<String>),
     None,
 }
 
 impl Value {
-    fn as_str(&self) -> Result<&str, EvalError> {
+    /// Return the value as a string, formatting numbers canonically.
+    fn as_str(&self) -> Result<Cow<'_, str>, EvalError> {
         match self {
-            Value::Str(s) => Ok(s),
+            Value::Str(s) => Ok(Cow::Borrowed(s)),
+            Value::Num(n) => Ok(Cow::Owned(format_num(*n))),
             _ => Err(EvalError::Other {
                 message: "Expected string value".to_string(),
                 line: 0,
             }),
         }
     }
+
+    /// Return the value as a number, parsing strings if necessary.
+    fn as_num(&self) -> Option<Number> {
+        match self {
+            Value::Num(n) => Some(*n),
+            Value::Str(s) => Number::parse(s.trim()),
+            _ => None,
+        }
+    }
+}
+
+/// A numeric literal. Integers are kept as `i64` so they don't lose precision
+/// beyond 2^53.
+#[derive(Debug, Clone, Copy)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+impl Number {
+    /// Parse an integer if possible, otherwise a float.
+    fn parse(s: &str) -> Option<Number> {
+        if let Ok(n) = s.parse::<i64>() {
+            return Some(Number::Int(n));
+        }
+        match s.parse::<f64>() {
+            // Reject words like "inf" and "nan" that `f64` would also accept.
+            Ok(n) if n.is_finite() => Some(Number::Float(n)),
+            _ => None,
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Number::Int(n) => n as f64,
+            Number::Float(n) => n,
+        }
+    }
+}
+
+impl PartialEq for Number {
+    /// Integers compare exactly; anything involving a float compares as `f64`.
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Number::Int(a), Number::Int(b)) => a == b,
+            _ => self.as_f64() == other.as_f64(),
+        }
+    }
+}
+
+/// Canonical string form of a number: integers have no decimal point.
+fn format_num(n: Number) -> String {
+    match n {
+        Number::Int(n) => n.to_string(),
+        // Also matches -0.0, to avoid printing "-0".
+        Number::Float(0.0) => "0".to_string(),
+        Number::Float(n) => n.to_string(),
+    }
 }
 
 #[derive(Debug, Error)]
@@ -355,7 +663,7 @@ pub enum EvalError {
     #[error("Interpolation depth exceeded: {message} (at line {line})")]
     InterpolationDepthExceeded { message: String, line: usize },
 
-    #[error("Type error for variable {var}: value {value} is not allowed (allowed: {allowed:?}) (at line {line})")]
+    #[error("Type error for variable {var}: value {value} is not allowed (allowed: {allowed:?}){} (at line {line})", did_you_mean(.value, .allowed))]
     TypeError {
         var: String,
         value: String,
@@ -364,12 +672,43 @@ pub enum EvalError {
     },
 
     #[error("Execution error: {message} (at line {line})")]
-    ExecutionError { message: String, line: usize },
+    ExecutionError {
+        message: String,
+        line: usize,
+        /// Exit code of the failed command, if it ran and exited normally.
+        code: Option<i32>,
+    },
 
     #[error("Error: {message} (at line {line})")]
     Other { message: String, line: usize },
 }
 
+/// Suggest the allowed value closest to `value`, if any is close enough to be a typo.
+fn did_you_mean(value: &str, allowed: &[String]) -> String {
+    allowed
+        .iter()
+        .map(|a| (levenshtein(value, a), a))
+        .filter(|(d, a)| *d <= (a.chars().count() / 3).max(1))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, a)| format!(" - did you mean '{}'?", a))
+        .unwrap_or_default()
+}
+
+/// Edit distance between two strings, counted in characters.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
 struct Context {
     base_cmd: Option<String>,
     config: Option<JsonValue>,
@@ -377,6 +716,18 @@ struct Context {
     defs: HashMap<String, String>,
     tasks: HashMap<String, Task>,
     groups: HashMap<String, Task>, // Group-level info.
+    isolated_env: bool,
+    env_vars: HashMap<String, String>, // Variables from `load-env` in isolated mode.
+    max_interp_depth: usize,
+    color: bool,
+    quiet: bool,
+    verbose: bool,
+    default_task: Option<(String, usize)>, // Task run when none is given, and its line.
+    tee: bool,
+    trace: bool,
+    trace_depth: Cell<usize>, // Nesting of the call being traced.
+    shell_cache: RefCell<HashMap<String, String>>, // Command -> stdout, for this run.
+    rng: RefCell<StdRng>,
 }
 
 impl Context {
@@ -388,6 +739,51 @@ impl Context {
             defs: HashMap::new(),
             tasks: HashMap::new(),
             groups: HashMap::new(),
+            isolated_env: false,
+            env_vars: HashMap::new(),
+            max_interp_depth: 10,
+            color: false,
+            quiet: false,
+            verbose: false,
+            default_task: None,
+            tee: false,
+            trace: false,
+            trace_depth: Cell::new(0),
+            shell_cache: RefCell::new(HashMap::new()),
+            rng: RefCell::new(rand::make_rng()),
+        }
+    }
+
+    /// Return the stdout of a command, running it only the first time a given
+    /// `key` is seen. With `nocache`, always run it and leave the cache alone.
+    /// Output of a command that failed is not cached, so it runs again next time.
+    fn cached_stdout(
+        &self,
+        key: &str,
+        nocache: bool,
+        run: impl FnOnce() -> io::Result<Output>,
+    ) -> io::Result<String> {
+        if !nocache {
+            if let Some(out) = self.shell_cache.borrow().get(key) {
+                return Ok(out.clone());
+            }
+        }
+        let output = run()?;
+        let out = String::from_utf8_lossy(&output.stdout).to_string();
+        if !nocache && output.status.success() {
+            self.shell_cache
+                .borrow_mut()
+                .insert(key.to_string(), out.clone());
+        }
+        Ok(out)
+    }
+
+    /// Wrap `text` in the color's escape codes if color output is enabled.
+    fn paint(&self, text: &str, color: Color) -> String {
+        if self.color {
+            format!("{}{}{}", color.code(), text, Color::Reset.code())
+        } else {
+            text.to_string()
         }
     }
 }
@@ -396,12 +792,18 @@ impl Context {
 struct Task {
     name: String, // Fully qualified (e.g. "eval.accuracy")
     title: String,
+    line: usize,
     desc: Option<String>,
     meta: HashMap<String, String>,
     cmd: Option<String>,
     shell: Option<String>,
+    log: Option<String>, // File that receives the command's stdout and stderr.
     params: Option<String>,
+    param_types: Vec<(String, String)>, // Typed params, bound in order from extra args.
     steps: Vec<String>,
+    pre: Vec<String>,             // Tasks run right before the command.
+    post: Vec<String>,            // Tasks run after the command, even if it fails.
+    default_task: Option<String>, // Groups only: member to run instead of all.
     props: HashMap<String, String>,
 }
 
@@ -409,18 +811,142 @@ struct Task {
 // Modified interpolate: now accepts a line number parameter.
 // ======================================================================
 
-fn interpolate(s: &str, env: &HashMap<String, String>, line: usize) -> Result<String, EvalError> {
+/// Stand-ins for the `{{` and `}}` escapes, and for literal dollar signs, while
+/// placeholders are being resolved.
+const LBRACE_ESCAPE: char = '\u{E000}';
+const RBRACE_ESCAPE: char = '\u{E001}';
+const DOLLAR_ESCAPE: char = '\u{E002}';
+
+/// A `{var}` placeholder.
+static PLACEHOLDER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{([^}]+)\}").unwrap());
+
+/// A `$VAR`, `${VAR}` or `${VAR:-default}` environment variable.
+static ENV_VAR_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\$(?:\{([A-Za-z_]\w*)(?::-([^}]*))?\}|([A-Za-z_]\w*))").unwrap());
+
+/// Replace `{{` and `}}` escapes with stand-ins so they are not treated as
+/// placeholders, and protect the shell's `$$` from `$VAR` expansion. Braces
+/// inside a `{var}` placeholder are left alone, so `{{{var}}}` becomes an
+/// escaped brace, the placeholder, and another escape.
+fn protect_escapes(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push(LBRACE_ESCAPE);
+            }
+            '{' => {
+                result.push(c);
+                for inner in chars.by_ref() {
+                    result.push(inner);
+                    if inner == '}' {
+                        break;
+                    }
+                }
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push(RBRACE_ESCAPE);
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                result.extend([DOLLAR_ESCAPE, DOLLAR_ESCAPE]);
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+fn restore_escapes(s: &str) -> String {
+    s.replace(LBRACE_ESCAPE, "{")
+        .replace(RBRACE_ESCAPE, "}")
+        .replace(DOLLAR_ESCAPE, "$")
+}
+
+/// Escape an evaluated value before storing it as a variable, so its braces and
+/// dollar signs stay literal when it is interpolated into another string.
+fn escape_value(s: &str) -> String {
+    s.replace('{', "{{")
+        .replace('}', "}}")
+        .replace('$', &DOLLAR_ESCAPE.to_string())
+}
+
+/// Undo `escape_value`, for variables read directly as symbols.
+fn unescape_value(s: &str) -> String {
+    s.replace("{{", "{")
+        .replace("}}", "}")
+        .replace(DOLLAR_ESCAPE, "$")
+}
+
+/// Replace `$VAR`, `${VAR}` and `${VAR:-default}` with values from the scoped
+/// environment or the process environment. The values are inserted literally,
+/// so braces or dollar signs in them are not interpolated again. Undefined
+/// variables without a default are errors.
+fn expand_env_vars(
+    s: &str,
+    scoped_env: &HashMap<String, String>,
+    line: usize,
+) -> Result<String, EvalError> {
+    let mut result = String::with_capacity(s.len());
+    let mut last = 0;
+    for cap in ENV_VAR_RE.captures_iter(s) {
+        let whole = cap.get(0).unwrap();
+        let var = cap.get(1).or_else(|| cap.get(3)).unwrap().as_str();
+        let default = cap.get(2).map(|m| m.as_str().to_string());
+        let val = scoped_env
+            .get(var)
+            .cloned()
+            .or_else(|| env::var(var).ok())
+            .or(default)
+            .ok_or_else(|| EvalError::UndefinedVariable {
+                message: format!("{} (in interpolation)", whole.as_str()),
+                line,
+            })?;
+        result.push_str(&s[last..whole.start()]);
+        result.extend(val.chars().map(|c| match c {
+            '{' => LBRACE_ESCAPE,
+            '}' => RBRACE_ESCAPE,
+            '$' => DOLLAR_ESCAPE,
+            c => c,
+        }));
+        last = whole.end();
+    }
+    result.push_str(&s[last..]);
+    Ok(result)
+}
+
+fn interpolate(
+    s: &str,
+    env: &HashMap<String, String>,
+    scoped_env: &HashMap<String, String>,
+    config: Option<&JsonValue>,
+    max_depth: usize,
+    line: usize,
+) -> Result<String, EvalError> {
     let mut result = s.to_string();
-    let re = Regex::new(r"\{([^}]+)\}").unwrap();
-    for _ in 0..10 {
-        if !re.is_match(&result) {
-            return Ok(result);
+    for _ in 0..max_depth {
+        result = protect_escapes(&result);
+        result = expand_env_vars(&result, scoped_env, line)?;
+        if !PLACEHOLDER_RE.is_match(&result) {
+            return Ok(restore_escapes(&result));
         }
         let mut replaced = result.clone();
-        for cap in re.captures_iter(&result) {
-            let key = &cap[1];
-            if let Some(val) = env.get(key) {
-                replaced = replaced.replace(&format!("{{{}}}", key), val);
+        for cap in PLACEHOLDER_RE.captures_iter(&result) {
+            // `{var:-default}` falls back to the literal default if `var` is missing.
+            let (key, default) = match cap[1].split_once(":-") {
+                Some((key, default)) => (key, Some(default)),
+                None => (&cap[1], None),
+            };
+            // `{conf:a.b}` reads the loaded config directly.
+            let val = match key.strip_prefix("conf:") {
+                Some(conf_key) => config.and_then(|cfg| lookup_config(cfg, conf_key)),
+                None => env.get(key).cloned(),
+            };
+            if let Some(val) = val.as_deref().or(default) {
+                replaced = replaced.replace(&cap[0], val);
             } else {
                 return Err(EvalError::UndefinedVariable {
                     message: format!("{} (in interpolation)", key),
@@ -430,13 +956,15 @@ fn interpolate(s: &str, env: &HashMap<String, String>, line: usize) -> Result<St
         }
         result = replaced;
     }
-    if re.is_match(&result) {
+    result = protect_escapes(&result);
+    result = expand_env_vars(&result, scoped_env, line)?;
+    if PLACEHOLDER_RE.is_match(&result) {
         Err(EvalError::InterpolationDepthExceeded {
-            message: "(in interpolation)".to_string(),
+            message: format!("limit is {} (in interpolation)", max_depth),
             line,
         })
     } else {
-        Ok(result)
+        Ok(restore_escapes(&result))
     }
 }
 
@@ -454,23 +982,61 @@ fn dumps(exp: &SExp, pretty: bool) -> String {
 
 fn dumps_pretty(exp: &SExp, indent: &str, level: usize) -> String {
     match exp {
-        SExp::String(s, _) | SExp::Symbol(s, _) => s.to_string(),
+        SExp::String(s, _) => quote_string(s),
+        SExp::Symbol(s, _) => s.to_string(),
+        SExp::Number(n, _) => format_num(*n),
         SExp::List(items, _) if items.is_empty() => "()".to_string(),
         SExp::List(items, _) => {
             let indent_str = indent.repeat(level + 1);
-            let items_str: Vec<String> = items
-                .iter()
-                .map(|x| dumps_pretty(x, indent, level + 1))
-                .collect();
-            format!(
-                "(\n{}{}\n{})",
-                indent_str,
-                items_str.join(&format!("\n{}", indent_str)),
-                indent.repeat(level)
-            )
+            let mut out = "(".to_string();
+            for item in items {
+                match item {
+                    SExp::Comment(comment, _) if comment.trailing => {
+                        out.push(' ');
+                        out.push_str(&comment.text);
+                    }
+                    _ => {
+                        out.push('\n');
+                        out.push_str(&indent_str);
+                        out.push_str(&dumps_pretty(item, indent, level + 1));
+                    }
+                }
+            }
+            out.push('\n');
+            out.push_str(&indent.repeat(level));
+            out.push(')');
+            out
         }
         SExp::Quoted(inner, _) => format!("'{}", dumps_pretty(inner, indent, level)),
+        SExp::Comment(comment, _) => comment.text.clone(),
+    }
+}
+
+/// Pretty-print top-level forms separated by blank lines, writing back the
+/// comments from `loads_all_with_comments`. A comment stays on the line it
+/// trails, or right above the form that follows it.
+pub fn dumps_pretty_all(forms: &[SExp], indent: &str) -> String {
+    let mut out = String::new();
+    let mut after_comment = false;
+    for form in forms {
+        match form {
+            SExp::Comment(comment, _) if comment.trailing && !out.is_empty() => {
+                out.push(' ');
+                out.push_str(&comment.text);
+            }
+            _ => {
+                if !out.is_empty() {
+                    out.push_str(if after_comment { "\n" } else { "\n\n" });
+                }
+                out.push_str(&dumps_pretty(form, indent, 0));
+            }
+        }
+        after_comment = matches!(form, SExp::Comment(comment, _) if !comment.trailing);
+    }
+    if !out.is_empty() {
+        out.push('\n');
     }
+    out
 }
 
 // ======================================================================
@@ -479,255 +1045,371 @@ fn dumps_pretty(exp: &SExp, indent: &str, level: usize) -> String {
 
 fn process_forms(forms: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
     for form in forms {
-        if let SExp::List(items, form_line) = form {
-            if items.is_empty() {
-                continue;
+        process_form(form, ctx)?;
+    }
+    Ok(())
+}
+
+/// Process every top-level form, collecting all errors instead of stopping at
+/// the first one. Each `def` and `types` entry is processed on its own, so one
+/// bad entry doesn't hide problems in the others.
+fn process_forms_collect(forms: &[SExp], ctx: &mut Context) -> Vec<EvalError> {
+    let mut errors = Vec::new();
+    for form in forms {
+        match form {
+            SExp::List(items, line) if matches!(items.first(), Some(SExp::Symbol(s, _)) if s == "def" || s == "types") => {
+                for entry in &items[1..] {
+                    let single = SExp::List(vec![items[0].clone(), entry.clone()], *line);
+                    errors.extend(process_form(&single, ctx).err());
+                }
             }
-            if let SExp::Symbol(ref form_name, _) = items[0] {
-                match form_name.as_str() {
-                    "base-cmd" => {
-                        if items.len() != 2 {
-                            return Err(EvalError::Other {
-                                message: "base-cmd requires one argument".to_string(),
-                                line: *form_line,
-                            });
-                        }
-                        if let SExp::String(s, _) = &items[1] {
-                            ctx.base_cmd = Some(s.clone());
+            _ => errors.extend(process_form(form, ctx).err()),
+        }
+    }
+    errors
+}
+
+fn process_form(form: &SExp, ctx: &mut Context) -> Result<(), EvalError> {
+    if let SExp::List(items, form_line) = form {
+        if items.is_empty() {
+            return Ok(());
+        }
+        if let SExp::Symbol(ref form_name, _) = items[0] {
+            match form_name.as_str() {
+                "base-cmd" => {
+                    if items.len() != 2 {
+                        return Err(EvalError::Other {
+                            message: "base-cmd requires one argument".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                    if let SExp::String(s, _) = &items[1] {
+                        ctx.base_cmd = Some(s.clone());
+                    } else {
+                        return Err(EvalError::Other {
+                            message: "base-cmd argument must be a string".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                }
+                "default" => {
+                    if items.len() != 2 {
+                        return Err(EvalError::Other {
+                            message: "default requires one argument".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                    if let SExp::Symbol(s, _) | SExp::String(s, _) = &items[1] {
+                        ctx.default_task = Some((s.clone(), *form_line));
+                    } else {
+                        return Err(EvalError::Other {
+                            message: "default argument must be a task name".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                }
+                "load-env" => {
+                    if items.len() != 2 {
+                        return Err(EvalError::Other {
+                            message: "load-env requires one argument".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                    if let SExp::String(fname, line) = &items[1] {
+                        let vars = load_env(fname).map_err(|e| EvalError::Other {
+                            message: format!("{} (in load-env)", e),
+                            line: *line,
+                        })?;
+                        if ctx.isolated_env {
+                            ctx.env_vars.extend(vars);
                         } else {
-                            return Err(EvalError::Other {
-                                message: "base-cmd argument must be a string".to_string(),
-                                line: *form_line,
-                            });
+                            for (key, value) in vars {
+                                env::set_var(key, value);
+                            }
                         }
+                    } else {
+                        return Err(EvalError::Other {
+                            message: "load-env argument must be a string".to_string(),
+                            line: *form_line,
+                        });
                     }
-                    "load-env" => {
-                        if items.len() != 2 {
-                            return Err(EvalError::Other {
-                                message: "load-env requires one argument".to_string(),
-                                line: *form_line,
-                            });
-                        }
-                        if let SExp::String(fname, line) = &items[1] {
-                            load_env(fname).map_err(|e| EvalError::Other {
-                                message: format!("{} (in load-env)", e),
+                }
+                "load-config" => {
+                    if items.len() != 2 {
+                        return Err(EvalError::Other {
+                            message: "load-config requires one argument".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                    if let SExp::String(fname, line) = &items[1] {
+                        let content = fs::read_to_string(fname).map_err(|e| {
+                            EvalError::Other { message: format!("Error reading config file '{}': {}. Please ensure the file exists and is accessible.", fname, e), line: *line }
+                        })?;
+                        let json =
+                            parse_config(fname, &content).map_err(|message| EvalError::Other {
+                                message,
                                 line: *line,
                             })?;
+                        ctx.config = Some(json);
+                    } else {
+                        return Err(EvalError::Other {
+                            message: "load-config argument must be a string".to_string(),
+                            line: *form_line,
+                        });
+                    }
+                }
+                "types" => {
+                    for type_def in &items[1..] {
+                        if let SExp::List(def_items, def_line) = type_def {
+                            if def_items.len() != 2 {
+                                return Err(EvalError::Other { message: format!("Malformed type definition: expected exactly 2 parts, but found {} in: {}", def_items.len(), dumps(type_def, false)), line: *def_line });
+                            }
+                            let type_name = if let SExp::Symbol(s, _) = &def_items[0] {
+                                s.clone()
+                            } else {
+                                return Err(EvalError::Other {
+                                    message: format!(
+                                        "Invalid type name in type definition: {}",
+                                        dumps(&def_items[0], false)
+                                    ),
+                                    line: *def_line,
+                                });
+                            };
+                            let allowed_val =
+                                eval_expr(&def_items[1], &ctx.defs, ctx).map_err(|e| {
+                                    EvalError::Other {
+                                        message: format!(
+                                            "Error evaluating allowed-values for type '{}': {}",
+                                            type_name, e
+                                        ),
+                                        line: *def_line,
+                                    }
+                                })?;
+                            let allowed = match allowed_val {
+                                Value::List(v) => v,
+                                Value::Str(s) => vec![s],
+                                Value::Num(n) => vec![format_num(n)],
+                                _ => {
+                                    return Err(EvalError::Other { message: format!("Type allowed-values for '{}' must be a list or string, but got: {}", type_name, dumps(&def_items[1], false)), line: *def_line });
+                                }
+                            };
+                            ctx.types.insert(type_name, allowed);
                         } else {
                             return Err(EvalError::Other {
-                                message: "load-env argument must be a string".to_string(),
-                                line: *form_line,
+                                message: format!(
+                                    "Invalid type definition: expected a list, got: {}",
+                                    dumps(type_def, false)
+                                ),
+                                line: type_def.line(),
                             });
                         }
                     }
-                    "load-config" => {
-                        if items.len() != 2 {
-                            return Err(EvalError::Other {
-                                message: "load-config requires one argument".to_string(),
-                                line: *form_line,
-                            });
-                        }
-                        if let SExp::String(fname, line) = &items[1] {
-                            let content = fs::read_to_string(fname).map_err(|e| {
-                                EvalError::Other { message: format!("Error reading config file '{}': {}. Please ensure the file exists and is accessible.", fname, e), line: *line }
-                            })?;
-                            let json: JsonValue =
-                                serde_json::from_str(&content).map_err(|e| EvalError::Other {
-                                    message: format!(
-                                        "Error parsing JSON in config file '{}': {}.",
-                                        fname, e
-                                    ),
-                                    line: *line,
-                                })?;
-                            ctx.config = Some(json);
-                        } else {
-                            return Err(EvalError::Other {
-                                message: "load-config argument must be a string".to_string(),
-                                line: *form_line,
-                            });
-                        }
-                    }
-                    "types" => {
-                        for type_def in &items[1..] {
-                            if let SExp::List(def_items, def_line) = type_def {
-                                if def_items.len() != 2 {
-                                    return Err(EvalError::Other { message: format!("Malformed type definition: expected exactly 2 parts, but found {} in: {}", def_items.len(), dumps(type_def, false)), line: *def_line });
-                                }
-                                let type_name = if let SExp::Symbol(s, _) = &def_items[0] {
-                                    s.clone()
-                                } else {
-                                    return Err(EvalError::Other {
-                                        message: format!(
-                                            "Invalid type name in type definition: {}",
-                                            dumps(&def_items[0], false)
-                                        ),
-                                        line: *def_line,
-                                    });
-                                };
-                                let allowed_val = eval_expr(&def_items[1], &ctx.defs, ctx)
-                                    .map_err(|e| EvalError::Other {
-                                        message: format!(
-                                            "Error evaluating allowed-values for type '{}': {}",
-                                            type_name, e
-                                        ),
-                                        line: *def_line,
-                                    })?;
-                                let allowed = match allowed_val {
-                                    Value::List(v) => v,
-                                    Value::Str(s) => vec![s],
-                                    _ => {
-                                        return Err(EvalError::Other { message: format!("Type allowed-values for '{}' must be a list or string, but got: {}", type_name, dumps(&def_items[1], false)), line: *def_line });
-                                    }
-                                };
-                                ctx.types.insert(type_name, allowed);
-                            } else {
+                }
+                "def" => {
+                    for def_item in &items[1..] {
+                        if let SExp::List(parts, def_line) = def_item {
+                            if parts.len() != 2 {
                                 return Err(EvalError::Other {
-                                    message: format!(
-                                        "Invalid type definition: expected a list, got: {}",
-                                        dumps(type_def, false)
-                                    ),
-                                    line: 0,
+                                    message: "Each def entry must have a key and a value"
+                                        .to_string(),
+                                    line: *def_line,
                                 });
                             }
-                        }
-                    }
-                    "def" => {
-                        for def_item in &items[1..] {
-                            if let SExp::List(parts, def_line) = def_item {
-                                if parts.len() != 2 {
+                            let (var_name, type_opt) = match &parts[0] {
+                                SExp::Symbol(s, _) => (s.clone(), None),
+                                SExp::List(inner, _) if inner.len() == 2 => {
+                                    let raw_var = if let SExp::Symbol(s, _) = &inner[0] {
+                                        s.trim_start_matches('[').to_string()
+                                    } else {
+                                        return Err(EvalError::Other {
+                                            message: "Invalid def key".to_string(),
+                                            line: *def_line,
+                                        });
+                                    };
+                                    let raw_type = if let SExp::Symbol(s, _) = &inner[1] {
+                                        s.trim_end_matches(']').to_string()
+                                    } else {
+                                        return Err(EvalError::Other {
+                                            message: "Invalid def type".to_string(),
+                                            line: *def_line,
+                                        });
+                                    };
+                                    (raw_var, Some(raw_type))
+                                }
+                                _ => {
                                     return Err(EvalError::Other {
-                                        message: "Each def entry must have a key and a value"
-                                            .to_string(),
+                                        message: "Invalid def key format".to_string(),
                                         line: *def_line,
-                                    });
+                                    })
                                 }
-                                let (var_name, type_opt) = match &parts[0] {
-                                    SExp::Symbol(s, _) => (s.clone(), None),
-                                    SExp::List(inner, _) if inner.len() == 2 => {
-                                        let raw_var = if let SExp::Symbol(s, _) = &inner[0] {
-                                            s.trim_start_matches('[').to_string()
-                                        } else {
-                                            return Err(EvalError::Other {
-                                                message: "Invalid def key".to_string(),
-                                                line: *def_line,
-                                            });
-                                        };
-                                        let raw_type = if let SExp::Symbol(s, _) = &inner[1] {
-                                            s.trim_end_matches(']').to_string()
-                                        } else {
-                                            return Err(EvalError::Other {
-                                                message: "Invalid def type".to_string(),
-                                                line: *def_line,
-                                            });
-                                        };
-                                        (raw_var, Some(raw_type))
-                                    }
+                            };
+                            let val = eval_expr(&parts[1], &ctx.defs, ctx).map_err(|e| {
+                                EvalError::Other {
+                                    message: format!(
+                                        "Error evaluating def entry for variable '{}': {}",
+                                        var_name, e
+                                    ),
+                                    line: *def_line,
+                                }
+                            })?;
+                            // `[type]` declares a list whose elements are all of `type`.
+                            if let Some(elem_type) =
+                                type_opt.as_deref().and_then(|t| t.strip_prefix('['))
+                            {
+                                let elems = match val {
+                                    Value::List(v) => v,
                                     _ => {
                                         return Err(EvalError::Other {
-                                            message: "Invalid def key format".to_string(),
+                                            message: format!(
+                                                "Variable '{}' of type [{}] must be a list",
+                                                var_name, elem_type
+                                            ),
                                             line: *def_line,
                                         })
                                     }
                                 };
-                                let val = eval_expr(&parts[1], &ctx.defs, ctx).map_err(|e| {
-                                    EvalError::Other {
-                                        message: format!(
-                                            "Error evaluating def entry for variable '{}': {}",
-                                            var_name, e
-                                        ),
-                                        line: *def_line,
+                                if let Some(allowed) = ctx.types.get(elem_type) {
+                                    if let Some(bad) = elems.iter().find(|e| !allowed.contains(e)) {
+                                        return Err(EvalError::TypeError {
+                                            var: var_name.clone(),
+                                            value: bad.clone(),
+                                            allowed: allowed.clone(),
+                                            line: *def_line,
+                                        });
                                     }
-                                })?;
-                                let val_str = match val {
-                                    Value::Str(s) => s,
-                                    _ => String::new(),
-                                };
-                                if let Some(tname) = type_opt {
-                                    if let Some(allowed) = ctx.types.get(&tname) {
-                                        if !allowed.contains(&val_str) {
-                                            return Err(EvalError::TypeError {
-                                                var: var_name.clone(),
-                                                value: val_str.clone(),
-                                                allowed: allowed.clone(),
-                                                line: *def_line,
-                                            });
-                                        }
+                                }
+                                ctx.defs.insert(var_name, escape_value(&elems.join(" ")));
+                                continue;
+                            }
+                            let val_str = match val {
+                                Value::Str(s) => s,
+                                Value::Num(n) => format_num(n),
+                                _ => String::new(),
+                            };
+                            if let Some(tname) = type_opt {
+                                if let Some(allowed) = ctx.types.get(&tname) {
+                                    if !allowed.contains(&val_str) {
+                                        return Err(EvalError::TypeError {
+                                            var: var_name.clone(),
+                                            value: val_str.clone(),
+                                            allowed: allowed.clone(),
+                                            line: *def_line,
+                                        });
                                     }
                                 }
-                                ctx.defs.insert(var_name, val_str);
-                            } else {
-                                return Err(EvalError::Other {
-                                    message: "Invalid def entry (expected a list)".to_string(),
-                                    line: 0,
-                                });
                             }
+                            ctx.defs.insert(var_name, escape_value(&val_str));
+                        } else {
+                            return Err(EvalError::Other {
+                                message: "Invalid def entry (expected a list)".to_string(),
+                                line: def_item.line(),
+                            });
                         }
                     }
-                    "task" => {
-                        let task = process_task(items, None).map_err(|e| EvalError::Other {
-                            message: format!("Error processing task: {}", e),
-                            line: items[0].line(),
-                        })?;
+                }
+                "task" => {
+                    let tasks = process_task(items, None).map_err(|e| EvalError::Other {
+                        message: format!("Error processing task: {}", e),
+                        line: items[0].line(),
+                    })?;
+                    for task in tasks {
                         ctx.tasks.insert(task.name.clone(), task);
                     }
-                    "group" => {
-                        if let SExp::List(items, group_line) = form {
-                            if items.len() < 3 {
-                                return Err(EvalError::Other {
-                                    message: "Group definition too short".to_string(),
-                                    line: *group_line,
-                                });
-                            }
-                            let group_name = if let SExp::Symbol(s, _) = &items[1] {
-                                s.clone()
-                            } else {
-                                return Err(EvalError::Other {
-                                    message: "Group name must be a symbol".to_string(),
-                                    line: *group_line,
-                                });
-                            };
-                            process_group(items, ctx).map_err(|e| EvalError::Other {
-                                message: format!("Error processing group '{}': {}", group_name, e),
+                }
+                "group" => {
+                    if let SExp::List(items, group_line) = form {
+                        if items.len() < 3 {
+                            return Err(EvalError::Other {
+                                message: "Group definition too short".to_string(),
                                 line: *group_line,
-                            })?;
+                            });
                         }
-                    }
-                    other => {
-                        return Err(EvalError::Other {
-                            message: format!("Unknown top-level form: {}", other),
-                            line: items[0].line(),
-                        });
+                        let group_name = if let SExp::Symbol(s, _) = &items[1] {
+                            s.clone()
+                        } else {
+                            return Err(EvalError::Other {
+                                message: "Group name must be a symbol".to_string(),
+                                line: *group_line,
+                            });
+                        };
+                        process_group(items, None, ctx).map_err(|e| EvalError::Other {
+                            message: format!("Error processing group '{}': {}", group_name, e),
+                            line: *group_line,
+                        })?;
                     }
                 }
-            } else {
-                return Err(EvalError::Other {
-                    message: "Expected a symbol at the beginning of the form".to_string(),
-                    line: *form_line,
-                });
+                other => {
+                    return Err(EvalError::Other {
+                        message: format!("Unknown top-level form: {}", other),
+                        line: items[0].line(),
+                    });
+                }
             }
         } else {
             return Err(EvalError::Other {
-                message: "Expected a list for a top-level form".to_string(),
-                line: 0,
+                message: "Expected a symbol at the beginning of the form".to_string(),
+                line: *form_line,
             });
         }
+    } else {
+        return Err(EvalError::Other {
+            message: "Expected a list for a top-level form".to_string(),
+            line: form.line(),
+        });
     }
     Ok(())
 }
 
 fn eval_expr(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result<Value, EvalError> {
+    if !ctx.trace {
+        return eval_expr_untraced(exp, env, ctx);
+    }
+    let SExp::List(list, line) = exp else {
+        return eval_expr_untraced(exp, env, ctx);
+    };
+    if list.is_empty() {
+        return eval_expr_untraced(exp, env, ctx);
+    }
+    let depth = ctx.trace_depth.get();
+    let indent = "  ".repeat(depth);
+    eprintln!("[trace] {}{} (line {})", indent, dumps(exp, false), line);
+    ctx.trace_depth.set(depth + 1);
+    let result = eval_expr_untraced(exp, env, ctx);
+    ctx.trace_depth.set(depth);
+    match &result {
+        Ok(val) => eprintln!("[trace] {}=> {:?}", indent, val),
+        Err(e) => eprintln!("[trace] {}=> error: {}", indent, e),
+    }
+    result
+}
+
+fn eval_expr_untraced(
+    exp: &SExp,
+    env: &HashMap<String, String>,
+    ctx: &Context,
+) -> Result<Value, EvalError> {
     match exp {
         SExp::String(s, _) => {
             // Interpolate the string and propagate errors with the line number from exp.
-            let interped = interpolate(s, env, exp.line()).map_err(|e| EvalError::Other {
+            let interped = interpolate(
+                s,
+                env,
+                &ctx.env_vars,
+                ctx.config.as_ref(),
+                ctx.max_interp_depth,
+                exp.line(),
+            )
+            .map_err(|e| EvalError::Other {
                 message: format!("{} (in string)", e),
                 line: exp.line(),
             })?;
             Ok(Value::Str(interped))
         }
+        SExp::Number(n, _) => Ok(Value::Num(*n)),
+        SExp::Comment(..) => Ok(Value::None),
         SExp::Symbol(s, line) => {
             if let Some(val) = env.get(s) {
-                Ok(Value::Str(val.clone()))
+                Ok(Value::Str(unescape_value(val)))
             } else {
                 Err(EvalError::UndefinedVariable {
                     message: s.clone(),
@@ -790,32 +1472,292 @@ fn eval_expr(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result
                         eval_expr(&list[3], env, ctx)
                     }
                 }
-                "equal?" => {
+                "when" | "unless" => {
+                    if list.len() < 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("{} requires a condition and a body", func),
+                            line: func_line,
+                        });
+                    }
+                    let cond = eval_expr(&list[1], env, ctx)?;
+                    let cond_val = cond.as_str().map_err(|_| EvalError::Other {
+                        message: "Condition must be a string".to_string(),
+                        line: list[1].line(),
+                    })?;
+                    if (cond_val.trim() == "true") != (func == "when") {
+                        return Ok(Value::None);
+                    }
+                    let mut last = Value::None;
+                    for body in &list[2..] {
+                        last = eval_expr(body, env, ctx)?;
+                    }
+                    Ok(last)
+                }
+                "let" => {
+                    if list.len() < 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "let requires a binding list and a body".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let SExp::List(bindings, bindings_line) = &list[1] else {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "let bindings must be a list".to_string(),
+                            line: list[1].line(),
+                        });
+                    };
+                    // Bindings are evaluated in order, so later ones can refer to
+                    // earlier ones. They only exist in this scope.
+                    let mut local_env = env.clone();
+                    for binding in bindings {
+                        let (name, expr) = match binding {
+                            SExp::List(pair, _) if pair.len() == 2 => match &pair[0] {
+                                SExp::Symbol(name, _) => (name, &pair[1]),
+                                _ => {
+                                    return Err(EvalError::InvalidFunctionCall {
+                                        message: "let binding name must be a symbol".to_string(),
+                                        line: binding.line(),
+                                    })
+                                }
+                            },
+                            _ => {
+                                return Err(EvalError::InvalidFunctionCall {
+                                    message: "Each let binding must have a name and a value"
+                                        .to_string(),
+                                    line: *bindings_line,
+                                })
+                            }
+                        };
+                        let val = eval_expr(expr, &local_env, ctx)?;
+                        let val_str = match val {
+                            Value::Str(s) => s,
+                            Value::Num(n) => format_num(n),
+                            _ => String::new(),
+                        };
+                        local_env.insert(name.clone(), escape_value(&val_str));
+                    }
+                    let mut last = Value::None;
+                    for body in &list[2..] {
+                        last = eval_expr(body, &local_env, ctx)?;
+                    }
+                    Ok(last)
+                }
+                "equal?" | "equal-ci?" => {
                     if list.len() != 3 {
                         return Err(EvalError::InvalidFunctionCall {
-                            message: "equal? requires exactly 2 arguments".to_string(),
+                            message: format!("{} requires exactly 2 arguments", func),
                             line: func_line,
                         });
                     }
                     let a = eval_expr(&list[1], env, ctx)?;
-                    let a_str = a
-                        .as_str()
-                        .map_err(|_| EvalError::Other {
-                            message: "Expected string".to_string(),
-                            line: list[1].line(),
-                        })?
-                        .trim();
+                    let a_str = a.as_str().map_err(|_| EvalError::Other {
+                        message: "Expected string".to_string(),
+                        line: list[1].line(),
+                    })?;
                     let b = eval_expr(&list[2], env, ctx)?;
-                    let b_str = b
-                        .as_str()
-                        .map_err(|_| EvalError::Other {
-                            message: "Expected string".to_string(),
+                    let b_str = b.as_str().map_err(|_| EvalError::Other {
+                        message: "Expected string".to_string(),
+                        line: list[2].line(),
+                    })?;
+                    // Compare numerically when either side is a number, so that
+                    // e.g. "1.0" and 1 are equal.
+                    let equal = match (&a, &b, a.as_num(), b.as_num()) {
+                        (Value::Num(_), _, Some(x), Some(y))
+                        | (_, Value::Num(_), Some(x), Some(y)) => x == y,
+                        _ if func == "equal-ci?" => {
+                            a_str.trim().to_lowercase() == b_str.trim().to_lowercase()
+                        }
+                        _ => a_str.trim() == b_str.trim(),
+                    };
+                    Ok(Value::Str(if equal { "true" } else { "false" }.to_string()))
+                }
+                "contains?" | "starts-with?" | "ends-with?" => {
+                    if list.len() != 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("{} requires exactly 2 arguments", func),
+                            line: func_line,
+                        });
+                    }
+                    let haystack = eval_str(&list[1], env, ctx)?;
+                    let needle = eval_str(&list[2], env, ctx)?;
+                    let result = match func {
+                        "contains?" => haystack.contains(&needle),
+                        "starts-with?" => haystack.starts_with(&needle),
+                        _ => haystack.ends_with(&needle),
+                    };
+                    Ok(Value::Str(result.to_string()))
+                }
+                "first" | "length" => {
+                    if list.len() != 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("{} requires one argument", func),
+                            line: func_line,
+                        });
+                    }
+                    let items = eval_list(&list[1], env, ctx)?;
+                    if func == "length" {
+                        return Ok(Value::Num(Number::Int(items.len() as i64)));
+                    }
+                    Ok(items.into_iter().next().map_or(Value::None, Value::Str))
+                }
+                "nth" => {
+                    if list.len() != 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "nth requires a list and an index".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let items = eval_list(&list[1], env, ctx)?;
+                    let index = eval_expr(&list[2], env, ctx)?
+                        .as_num()
+                        .map(Number::as_f64)
+                        .filter(|n| n.fract() == 0.0 && *n >= 0.0)
+                        .ok_or_else(|| EvalError::Other {
+                            message: "nth index must be a non-negative integer".to_string(),
                             line: list[2].line(),
-                        })?
-                        .trim();
-                    Ok(Value::Str(
-                        if a_str == b_str { "true" } else { "false" }.to_string(),
-                    ))
+                        })?;
+                    let len = items.len();
+                    items
+                        .into_iter()
+                        .nth(index as usize)
+                        .map(Value::Str)
+                        .ok_or_else(|| EvalError::InvalidFunctionCall {
+                            message: format!(
+                                "nth index {} out of range for list of length {}",
+                                index, len
+                            ),
+                            line: func_line,
+                        })
+                }
+                "join" => {
+                    if list.len() != 2 && list.len() != 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "join requires a list and an optional separator".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let items = eval_list(&list[1], env, ctx)?;
+                    let sep = match list.get(2) {
+                        Some(exp) => eval_str(exp, env, ctx)?,
+                        None => " ".to_string(),
+                    };
+                    Ok(Value::Str(items.join(&sep)))
+                }
+                "split" => {
+                    if list.len() != 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "split requires a separator and a string".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let sep = eval_str(&list[1], env, ctx)?;
+                    let s = eval_str(&list[2], env, ctx)?;
+                    // An empty separator splits into characters.
+                    let parts = if sep.is_empty() {
+                        s.chars().map(String::from).collect()
+                    } else {
+                        s.split(sep.as_str()).map(String::from).collect()
+                    };
+                    Ok(Value::List(parts))
+                }
+                "replace" => {
+                    if list.len() != 4 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "replace requires a pattern, a replacement and a string"
+                                .to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let from = eval_str(&list[1], env, ctx)?;
+                    let to = eval_str(&list[2], env, ctx)?;
+                    let s = eval_str(&list[3], env, ctx)?;
+                    if from.is_empty() {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "replace pattern must not be empty".to_string(),
+                            line: list[1].line(),
+                        });
+                    }
+                    Ok(Value::Str(s.replace(&from, &to)))
+                }
+                "echo" => {
+                    if list.len() != 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "echo requires one argument".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let val = eval_expr(&list[1], env, ctx)?;
+                    if !ctx.quiet {
+                        let shown = match &val {
+                            Value::Str(s) => format!("{:?}", s),
+                            Value::Num(n) => format_num(*n),
+                            Value::List(v) => format!("{:?}", v),
+                            Value::None => "None".to_string(),
+                        };
+                        eprintln!("[echo line {}] {}", func_line, shown);
+                    }
+                    Ok(val)
+                }
+                "upper" | "lower" => {
+                    if list.len() != 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("{} requires one argument", func),
+                            line: func_line,
+                        });
+                    }
+                    let s = eval_str(&list[1], env, ctx)?;
+                    Ok(Value::Str(if func == "upper" {
+                        s.to_uppercase()
+                    } else {
+                        s.to_lowercase()
+                    }))
+                }
+                "map" => {
+                    if list.len() != 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "map requires an operation and a list".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let items = eval_list(&list[2], env, ctx)?;
+                    // `'f` is shorthand for `(f _)`. Any other expression is evaluated
+                    // once per element with the element bound to `_`.
+                    let body = match &list[1] {
+                        SExp::Quoted(inner, line) => match &**inner {
+                            SExp::Symbol(..) => SExp::List(
+                                vec![(**inner).clone(), SExp::Symbol("_".to_string(), *line)],
+                                *line,
+                            ),
+                            _ => {
+                                return Err(EvalError::InvalidFunctionCall {
+                                    message:
+                                        "map operation must be a quoted symbol or an expression"
+                                            .to_string(),
+                                    line: *line,
+                                })
+                            }
+                        },
+                        other => other.clone(),
+                    };
+                    let mut local_env = env.clone();
+                    let mut result = Vec::new();
+                    for item in items {
+                        local_env.insert("_".to_string(), escape_value(&item));
+                        match eval_expr(&body, &local_env, ctx)? {
+                            Value::Str(s) => result.push(s),
+                            Value::Num(n) => result.push(format_num(n)),
+                            // Dropping None lets `when`/`unless` filter elements.
+                            Value::None => {}
+                            Value::List(_) => {
+                                return Err(EvalError::Other {
+                                    message: "map operation must return a string, not a list"
+                                        .to_string(),
+                                    line: body.line(),
+                                })
+                            }
+                        }
+                    }
+                    Ok(Value::List(result))
                 }
                 "env" => {
                     if list.len() != 2 {
@@ -831,6 +1773,9 @@ fn eval_expr(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result
                             line: list[1].line(),
                         })?
                         .to_string();
+                    if let Some(val) = ctx.env_vars.get(&var) {
+                        return Ok(Value::Str(val.clone()));
+                    }
                     match env::var(&var) {
                         Ok(val) => Ok(Value::Str(val)),
                         Err(_) => Ok(Value::None),
@@ -850,79 +1795,255 @@ fn eval_expr(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result
                             line: list[1].line(),
                         })?
                         .to_string();
-                    if let Some(cfg) = &ctx.config {
-                        if let Some(val) = cfg.get(&key) {
-                            if let Some(s) = val.as_str() {
-                                return Ok(Value::Str(s.to_string()));
-                            }
-                        }
+                    match ctx.config.as_ref().and_then(|cfg| lookup_config(cfg, &key)) {
+                        Some(s) => Ok(Value::Str(s)),
+                        None => Ok(Value::None),
                     }
-                    Ok(Value::None)
                 }
                 "git-root" => {
-                    let output = Command::new("git")
-                        .args(["rev-parse", "--show-toplevel"])
-                        .output()
+                    let output = ctx
+                        .cached_stdout("git rev-parse --show-toplevel", false, || {
+                            Command::new("git")
+                                .args(["rev-parse", "--show-toplevel"])
+                                .output()
+                        })
                         .map_err(|e| EvalError::ExecutionError {
                             message: format!("Git error: {}", e),
                             line: func_line,
+                            code: None,
                         })?;
-                    let s = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                    Ok(Value::Str(s))
+                    Ok(Value::Str(output.trim().to_string()))
                 }
                 "current-timestamp" => {
                     let now = Utc::now().to_rfc3339();
                     Ok(Value::Str(now))
                 }
-                "shell" => {
+                "current-date" => {
+                    if list.len() > 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "current-date takes at most one argument".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let now = Utc::now();
+                    let Some(fmt_arg) = list.get(1) else {
+                        return Ok(Value::Str(now.to_rfc3339()));
+                    };
+                    let fmt = eval_str(fmt_arg, env, ctx)?;
+                    // Formatting with an invalid specifier would panic, so check first.
+                    let items: Vec<_> = StrftimeItems::new(&fmt).collect();
+                    if items.contains(&Item::Error) {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("Invalid date format: {}", fmt),
+                            line: fmt_arg.line(),
+                        });
+                    }
+                    Ok(Value::Str(
+                        now.format_with_items(items.into_iter()).to_string(),
+                    ))
+                }
+                "glob" => {
                     if list.len() != 2 {
                         return Err(EvalError::InvalidFunctionCall {
-                            message: "shell requires one argument".to_string(),
+                            message: "glob requires one argument".to_string(),
                             line: func_line,
                         });
                     }
-                    let cmd_str = eval_expr(&list[1], env, ctx)?
-                        .as_str()
-                        .map_err(|_| EvalError::Other {
-                            message: "Expected string".to_string(),
-                            line: list[1].line(),
-                        })?
-                        .to_string();
-                    let output = Command::new("sh")
-                        .arg("-c")
-                        .arg(&cmd_str)
-                        .output()
-                        .map_err(|e| EvalError::ExecutionError {
-                            message: format!("Shell execution error: {}", e),
+                    let pattern = eval_str(&list[1], env, ctx)?;
+                    let paths = glob::glob(&pattern).map_err(|e| EvalError::Other {
+                        message: format!("Invalid glob pattern '{}': {}", pattern, e),
+                        line: func_line,
+                    })?;
+                    let mut matches = Vec::new();
+                    for path in paths {
+                        let path = path.map_err(|e| EvalError::Other {
+                            message: format!("Failed to read glob match: {}", e),
                             line: func_line,
                         })?;
-                    let s = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                    Ok(Value::Str(s))
+                        matches.push(path.to_string_lossy().into_owned());
+                    }
+                    matches.sort();
+                    Ok(Value::List(matches))
                 }
-                "from-shell" => {
-                    if list.len() != 2 {
+                "uuid" => {
+                    if list.len() != 1 {
                         return Err(EvalError::InvalidFunctionCall {
-                            message: "from-shell requires one argument".to_string(),
+                            message: "uuid takes no arguments".to_string(),
                             line: func_line,
                         });
                     }
-                    let cmd_str = eval_expr(&list[1], env, ctx)?
-                        .as_str()
-                        .map_err(|_| EvalError::Other {
-                            message: "Expected string".to_string(),
+                    Ok(Value::Str(uuid::Uuid::new_v4().to_string()))
+                }
+                "random" => {
+                    if list.len() > 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "random takes at most one argument".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    // Evaluate the bound before borrowing the RNG, as it may call `random` too.
+                    let bound = match list.get(1) {
+                        Some(arg) => match eval_expr(arg, env, ctx)?.as_num().map(Number::as_f64) {
+                            Some(b) if b >= 1.0 && b.fract() == 0.0 => Some(b as u64),
+                            _ => {
+                                return Err(EvalError::InvalidFunctionCall {
+                                    message: "random bound must be a positive integer".to_string(),
+                                    line: arg.line(),
+                                })
+                            }
+                        },
+                        None => None,
+                    };
+                    let mut rng = ctx.rng.borrow_mut();
+                    let n = match bound {
+                        Some(b) => rng.random_range(0..b),
+                        None => u64::from(rng.random::<u32>()),
+                    };
+                    Ok(Value::Str(n.to_string()))
+                }
+                "read-file" => {
+                    if list.len() != 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "read-file requires one argument".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let path = eval_str(&list[1], env, ctx)?;
+                    let content = fs::read_to_string(&path).map_err(|e| EvalError::Other {
+                        message: format!("Failed to read file {}: {}", path, e),
+                        line: func_line,
+                    })?;
+                    Ok(Value::Str(content.trim().to_string()))
+                }
+                "json-get" => {
+                    if list.len() != 3 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "json-get requires a file and a key".to_string(),
+                            line: func_line,
+                        });
+                    }
+                    let path = eval_str(&list[1], env, ctx)?;
+                    let key = eval_str(&list[2], env, ctx)?;
+                    let content = fs::read_to_string(&path).map_err(|e| EvalError::Other {
+                        message: format!("Failed to read file {}: {}", path, e),
+                        line: func_line,
+                    })?;
+                    let json: JsonValue =
+                        serde_json::from_str(&content).map_err(|e| EvalError::Other {
+                            message: format!("Failed to parse JSON in {}: {}", path, e),
+                            line: func_line,
+                        })?;
+                    Ok(lookup_config(&json, &key).map_or(Value::None, Value::Str))
+                }
+                "shell" | "shell-nocache" => {
+                    if list.len() != 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("{} requires one argument", func),
+                            line: func_line,
+                        });
+                    }
+                    let cmd_str = eval_expr(&list[1], env, ctx)?
+                        .as_str()
+                        .map_err(|_| EvalError::Other {
+                            message: "Expected string".to_string(),
                             line: list[1].line(),
                         })?
                         .to_string();
-                    let output = Command::new("sh")
-                        .arg("-c")
-                        .arg(&cmd_str)
+                    let output = ctx
+                        .cached_stdout(&cmd_str, func == "shell-nocache", || {
+                            Command::new("sh")
+                                .arg("-c")
+                                .arg(&cmd_str)
+                                .envs(&ctx.env_vars)
+                                .output()
+                        })
+                        .map_err(|e| EvalError::ExecutionError {
+                            message: format!("Shell execution error: {}", e),
+                            line: func_line,
+                            code: None,
+                        })?;
+                    Ok(Value::Str(output.trim().to_string()))
+                }
+                "shell-args" => {
+                    if list.len() < 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "shell-args requires a program and optional arguments"
+                                .to_string(),
+                            line: func_line,
+                        });
+                    }
+                    // Each argument is passed to the program as-is, without a shell,
+                    // so values with spaces or quotes can't break the command. Lists
+                    // are spliced in as separate arguments.
+                    let mut argv = Vec::new();
+                    for arg in &list[1..] {
+                        match eval_expr(arg, env, ctx)? {
+                            Value::List(items) => argv.extend(items),
+                            val => argv.push(
+                                val.as_str()
+                                    .map_err(|_| EvalError::Other {
+                                        message: "Expected string".to_string(),
+                                        line: arg.line(),
+                                    })?
+                                    .to_string(),
+                            ),
+                        }
+                    }
+                    let Some((program, args)) = argv.split_first() else {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: "shell-args requires a program".to_string(),
+                            line: func_line,
+                        });
+                    };
+                    let output = Command::new(program)
+                        .args(args)
+                        .envs(&ctx.env_vars)
                         .output()
                         .map_err(|e| EvalError::ExecutionError {
-                            message: format!("from-shell error: {}", e),
+                            message: format!("shell-args error running '{}': {}", program, e),
+                            line: func_line,
+                            code: None,
+                        })?;
+                    let s = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                    Ok(Value::Str(s))
+                }
+                "from-shell" | "from-shell-lines" => {
+                    if list.len() != 2 {
+                        return Err(EvalError::InvalidFunctionCall {
+                            message: format!("{} requires one argument", func),
+                            line: func_line,
+                        });
+                    }
+                    let cmd_str = eval_expr(&list[1], env, ctx)?
+                        .as_str()
+                        .map_err(|_| EvalError::Other {
+                            message: "Expected string".to_string(),
+                            line: list[1].line(),
+                        })?
+                        .to_string();
+                    let s = ctx
+                        .cached_stdout(&cmd_str, false, || {
+                            Command::new("sh")
+                                .arg("-c")
+                                .arg(&cmd_str)
+                                .envs(&ctx.env_vars)
+                                .output()
+                        })
+                        .map_err(|e| EvalError::ExecutionError {
+                            message: format!("{} error: {}", func, e),
                             line: func_line,
+                            code: None,
                         })?;
-                    let s = String::from_utf8_lossy(&output.stdout);
-                    let parts: Vec<String> = s.split_whitespace().map(|s| s.to_string()).collect();
+                    let parts: Vec<String> = if func == "from-shell-lines" {
+                        // Keep whole lines so paths with spaces survive.
+                        let mut lines: Vec<String> = s.lines().map(|l| l.to_string()).collect();
+                        while lines.last().is_some_and(|l| l.is_empty()) {
+                            lines.pop();
+                        }
+                        lines
+                    } else {
+                        s.split_whitespace().map(|s| s.to_string()).collect()
+                    };
                     Ok(Value::List(parts))
                 }
                 _ => Err(EvalError::UnknownFunction {
@@ -938,6 +2059,7 @@ fn eval_expr(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result
                     match item {
                         SExp::String(s, _) => vec.push(s.clone()),
                         SExp::Symbol(s, _) => vec.push(s.clone()),
+                        SExp::Number(n, _) => vec.push(format_num(*n)),
                         _ => {
                             return Err(EvalError::NonLiteralInQuoted {
                                 message: "(in quoted expression)".to_string(),
@@ -952,7 +2074,67 @@ fn eval_expr(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result
         },
     }
 }
-fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Task, EvalError> {
+
+/// Evaluate an expression that must produce a string (or a number).
+fn eval_str(exp: &SExp, env: &HashMap<String, String>, ctx: &Context) -> Result<String, EvalError> {
+    eval_expr(exp, env, ctx)?
+        .as_str()
+        .map(|s| s.into_owned())
+        .map_err(|_| EvalError::Other {
+            message: "Expected string".to_string(),
+            line: exp.line(),
+        })
+}
+
+/// Evaluate an expression that must produce a list.
+fn eval_list(
+    exp: &SExp,
+    env: &HashMap<String, String>,
+    ctx: &Context,
+) -> Result<Vec<String>, EvalError> {
+    match eval_expr(exp, env, ctx)? {
+        Value::List(items) => Ok(items),
+        _ => Err(EvalError::Other {
+            message: "Expected list".to_string(),
+            line: exp.line(),
+        }),
+    }
+}
+
+/// Parse `(name type)` parameter declarations. The type is `int`, `float`, `str` or
+/// the name of a `types` entry (optionally written as `{name}`); it defaults to `str`.
+fn parse_param_types(items: &[SExp]) -> Result<Vec<(String, String)>, EvalError> {
+    let mut params = Vec::new();
+    for item in items {
+        let (name, ty) = match item {
+            SExp::Symbol(name, _) => (name, "str"),
+            SExp::List(parts, _) => match parts.as_slice() {
+                [SExp::Symbol(name, _)] => (name, "str"),
+                [SExp::Symbol(name, _), SExp::Symbol(ty, _)] => {
+                    (name, ty.trim_start_matches('{').trim_end_matches('}'))
+                }
+                _ => {
+                    return Err(EvalError::Other {
+                        message: format!("Invalid parameter: {}", dumps(item, false)),
+                        line: item.line(),
+                    })
+                }
+            },
+            _ => {
+                return Err(EvalError::Other {
+                    message: format!("Invalid parameter: {}", dumps(item, false)),
+                    line: item.line(),
+                })
+            }
+        };
+        params.push((name.clone(), ty.to_string()));
+    }
+    Ok(params)
+}
+
+/// Build a task from its definition. A `matrix` property expands it into one
+/// task per combination of values, so this can return several tasks.
+fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Vec<Task>, EvalError> {
     if items.len() < 3 {
         return Err(EvalError::Other {
             message: "Task definition too short".to_string(),
@@ -982,15 +2164,22 @@ fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Task, EvalError
             })
         }
     };
+    let mut matrix: Vec<(String, Vec<String>)> = Vec::new();
     let mut task = Task {
         name: name.clone(),
         title,
+        line: items[0].line(),
         desc: None,
         meta: HashMap::new(),
         cmd: None,
         shell: None,
+        log: None,
         params: None,
+        param_types: vec![],
         steps: vec![],
+        pre: vec![],
+        post: vec![],
+        default_task: None,
         props: HashMap::new(),
     };
     for prop in &items[3..] {
@@ -1021,6 +2210,7 @@ fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Task, EvalError
                                 };
                                 let mval = match &pair[1] {
                                     SExp::Symbol(s, _) | SExp::String(s, _) => s.clone(),
+                                    SExp::Number(n, _) => format_num(*n),
                                     _ => continue,
                                 };
                                 task.meta.insert(mkey, mval);
@@ -1042,10 +2232,19 @@ fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Task, EvalError
                         }
                     }
                 }
+                "log" => {
+                    if prop_items.len() >= 2 {
+                        if let SExp::String(s, _) = &prop_items[1] {
+                            task.log = Some(s.clone());
+                        }
+                    }
+                }
                 "params" => {
                     if prop_items.len() >= 2 {
                         if let SExp::String(s, _) = &prop_items[1] {
                             task.params = Some(s.clone());
+                        } else {
+                            task.param_types = parse_param_types(&prop_items[1..])?;
                         }
                     }
                 }
@@ -1056,12 +2255,54 @@ fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Task, EvalError
                         }
                     }
                 }
+                "matrix" => {
+                    for entry in &prop_items[1..] {
+                        let SExp::List(entry_items, entry_line) = entry else {
+                            continue;
+                        };
+                        let Some(SExp::Symbol(var, _)) = entry_items.first() else {
+                            return Err(EvalError::Other {
+                                message: "Matrix variable name must be a symbol".to_string(),
+                                line: *entry_line,
+                            });
+                        };
+                        let values: Vec<String> = entry_items[1..]
+                            .iter()
+                            .filter_map(|v| match v {
+                                SExp::String(s, _) | SExp::Symbol(s, _) => Some(s.clone()),
+                                SExp::Number(n, _) => Some(format_num(*n)),
+                                _ => None,
+                            })
+                            .collect();
+                        if values.is_empty() {
+                            return Err(EvalError::Other {
+                                message: format!("Matrix variable '{}' has no values", var),
+                                line: *entry_line,
+                            });
+                        }
+                        matrix.push((var.clone(), values));
+                    }
+                }
+                "pre" | "post" => {
+                    let hooks = if key == "pre" {
+                        &mut task.pre
+                    } else {
+                        &mut task.post
+                    };
+                    for hook in &prop_items[1..] {
+                        if let SExp::Symbol(s, _) = hook {
+                            hooks.push(s.clone());
+                        }
+                    }
+                }
                 _ => {
                     if prop_items.len() >= 2 {
                         if let SExp::String(s, _) = &prop_items[1] {
                             task.props.insert(key.to_string(), s.clone());
                         } else if let SExp::Symbol(s, _) = &prop_items[1] {
                             task.props.insert(key.to_string(), s.clone());
+                        } else if let SExp::Number(n, _) = &prop_items[1] {
+                            task.props.insert(key.to_string(), format_num(*n));
                         }
                     }
                 }
@@ -1072,21 +2313,44 @@ fn process_task(items: &[SExp], parent: Option<&Task>) -> Result<Task, EvalError
         if task.cmd.is_none() {
             task.cmd = p.cmd.clone();
         }
-        if task.params.is_none() {
+        if task.log.is_none() {
+            task.log = p.log.clone();
+        }
+        if task.params.is_none() && task.param_types.is_empty() {
             task.params = p.params.clone();
+            task.param_types = p.param_types.clone();
+        }
+    }
+    // Expand the cartesian product of the matrix, suffixing the name with each
+    // value and making the variable available to interpolation.
+    let mut variants = vec![task];
+    for (var, values) in &matrix {
+        let mut expanded = Vec::new();
+        for variant in &variants {
+            for value in values {
+                let mut t = variant.clone();
+                t.name = format!("{}.{}", variant.name, value);
+                t.props.insert(var.clone(), value.clone());
+                expanded.push(t);
+            }
         }
+        variants = expanded;
     }
-    Ok(task)
+    Ok(variants)
 }
 
-fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
+fn process_group(
+    items: &[SExp],
+    parent: Option<&Task>,
+    ctx: &mut Context,
+) -> Result<(), EvalError> {
     if items.len() < 3 {
         return Err(EvalError::Other {
             message: "Group definition too short".to_string(),
             line: items[0].line(),
         });
     }
-    let group_name = match &items[1] {
+    let raw_name = match &items[1] {
         SExp::Symbol(s, _) => s.clone(),
         _ => {
             return Err(EvalError::Other {
@@ -1095,6 +2359,11 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
             })
         }
     };
+    let group_name = if let Some(p) = parent {
+        format!("{}.{}", p.name, raw_name)
+    } else {
+        raw_name
+    };
     let group_title = match &items[2] {
         SExp::String(s, _) => s.clone(),
         _ => {
@@ -1107,12 +2376,18 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
     let mut group_task = Task {
         name: group_name.clone(),
         title: group_title,
+        line: items[0].line(),
         desc: None,
         meta: HashMap::new(),
         cmd: None,
         shell: None,
+        log: None,
         params: None,
+        param_types: vec![],
         steps: vec![],
+        pre: vec![],
+        post: vec![],
+        default_task: None,
         props: HashMap::new(),
     };
     for prop in &items[3..] {
@@ -1123,6 +2398,8 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
             let SExp::Symbol(key, _) = &prop_items[0] else {
                 continue;
             };
+            // Same layout as the properties in `process_task`.
+            #[allow(clippy::collapsible_match)]
             match key.as_str() {
                 "desc" => {
                     if prop_items.len() >= 2 {
@@ -1141,6 +2418,7 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
                                 };
                                 let mval = match &pair[1] {
                                     SExp::Symbol(s, _) | SExp::String(s, _) => s.clone(),
+                                    SExp::Number(n, _) => format_num(*n),
                                     _ => continue,
                                 };
                                 group_task.meta.insert(mkey, mval);
@@ -1152,6 +2430,8 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
                     if prop_items.len() >= 2 {
                         if let SExp::String(s, _) = &prop_items[1] {
                             group_task.params = Some(s.clone());
+                        } else {
+                            group_task.param_types = parse_param_types(&prop_items[1..])?;
                         }
                     }
                 }
@@ -1162,18 +2442,56 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
                         }
                     }
                 }
+                "log" => {
+                    if prop_items.len() >= 2 {
+                        if let SExp::String(s, _) = &prop_items[1] {
+                            group_task.log = Some(s.clone());
+                        }
+                    }
+                }
+                "steps" => {
+                    for step in &prop_items[1..] {
+                        if let SExp::Symbol(s, _) = step {
+                            group_task.steps.push(s.clone());
+                        }
+                    }
+                }
+                "default" => {
+                    if prop_items.len() >= 2 {
+                        if let SExp::Symbol(s, _) = &prop_items[1] {
+                            group_task.default_task = Some(format!("{}.{}", group_name, s));
+                        }
+                    }
+                }
                 _ => {}
             }
         }
     }
+    if let Some(p) = parent {
+        if group_task.cmd.is_none() {
+            group_task.cmd = p.cmd.clone();
+        }
+        if group_task.log.is_none() {
+            group_task.log = p.log.clone();
+        }
+        if group_task.params.is_none() && group_task.param_types.is_empty() {
+            group_task.params = p.params.clone();
+            group_task.param_types = p.param_types.clone();
+        }
+    }
     ctx.groups.insert(group_name.clone(), group_task.clone());
     for prop in &items[3..] {
         if let SExp::List(prop_items, _) = prop {
             if !prop_items.is_empty() {
                 if let SExp::Symbol(key, _) = &prop_items[0] {
-                    if key.as_str() == "task" {
-                        let task = process_task(prop_items, Some(&group_task))?;
-                        ctx.tasks.insert(task.name.clone(), task);
+                    match key.as_str() {
+                        "task" => {
+                            for task in process_task(prop_items, Some(&group_task))? {
+                                ctx.tasks.insert(task.name.clone(), task);
+                            }
+                        }
+                        "group" => process_group(prop_items, Some(&group_task), ctx)?,
+                        _ => {}
                     }
                 }
             }
@@ -1186,10 +2504,11 @@ fn process_group(items: &[SExp], ctx: &mut Context) -> Result<(), EvalError> {
 // Environment loader (strips quotes from values)
 // ======================================================================
 
-fn load_env(fname: &str) -> Result<(), EvalError> {
+fn load_env(fname: &str) -> Result<Vec<(String, String)>, EvalError> {
     let content = fs::read_to_string(fname).map_err(|e| {
         EvalError::Other { message: format!("Error reading .env file '{}': {}. Please ensure the file exists in the expected location.", fname, e), line: 0 }
     })?;
+    let mut vars = Vec::new();
     for line in content.lines() {
         let trimmed = line.trim();
         if trimmed.starts_with('#') || trimmed.is_empty() {
@@ -1204,23 +2523,108 @@ fn load_env(fname: &str) -> Result<(), EvalError> {
                 } else {
                     raw_value
                 };
-            env::set_var(key, value);
+            vars.push((key.to_string(), value.to_string()));
         }
     }
-    Ok(())
+    Ok(vars)
+}
+
+// ======================================================================
+// Config loader (JSON, or TOML for files with a `.toml` extension)
+// ======================================================================
+
+fn parse_config(fname: &str, content: &str) -> Result<JsonValue, String> {
+    let is_toml = Path::new(fname)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+    if is_toml {
+        toml::from_str(content)
+            .map_err(|e| format!("Error parsing TOML in config file '{}': {}.", fname, e))
+    } else {
+        serde_json::from_str(content)
+            .map_err(|e| format!("Error parsing JSON in config file '{}': {}.", fname, e))
+    }
+}
+
+/// Look up a dotted key (e.g. "training.batch_size") by walking nested objects.
+///
+/// Returns `None` if any segment is missing or the leaf is not a scalar.
+fn lookup_config(cfg: &JsonValue, key: &str) -> Option<String> {
+    let mut current = cfg;
+    for segment in key.split('.') {
+        current = current.get(segment)?;
+    }
+    match current {
+        JsonValue::String(s) => Some(s.clone()),
+        JsonValue::Number(n) => Some(n.to_string()),
+        JsonValue::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Top-level defs that no string placeholder or symbol in the file refers to.
+/// Names built dynamically (e.g. from shell output) are not detected.
+fn unused_defs(forms: &[SExp], ctx: &Context) -> Vec<String> {
+    fn collect(exp: &SExp, re: &Regex, used: &mut HashSet<String>) {
+        match exp {
+            SExp::String(s, _) => {
+                for cap in re.captures_iter(s) {
+                    let key = cap[1].split_once(":-").map_or(&cap[1], |(key, _)| key);
+                    used.insert(key.to_string());
+                }
+            }
+            SExp::Symbol(s, _) => {
+                used.insert(s.clone());
+            }
+            SExp::Number(..) | SExp::Comment(..) => {}
+            SExp::Quoted(inner, _) => collect(inner, re, used),
+            SExp::List(items, _) => {
+                let is_def = matches!(items.first(), Some(SExp::Symbol(s, _)) if s == "def");
+                for item in items {
+                    match item {
+                        // Skip the names being defined, only their values are uses.
+                        SExp::List(entry, _) if is_def && entry.len() == 2 => {
+                            collect(&entry[1], re, used)
+                        }
+                        _ => collect(item, re, used),
+                    }
+                }
+            }
+        }
+    }
+
+    let mut used = HashSet::new();
+    for form in forms {
+        collect(form, &PLACEHOLDER_RE, &mut used);
+    }
+    let mut unused: Vec<_> = ctx
+        .defs
+        .keys()
+        .filter(|name| !used.contains(*name))
+        .cloned()
+        .collect();
+    unused.sort();
+    unused
 }
 
 // ======================================================================
 // Task execution (printing group and task info; errors include line numbers)
 // ======================================================================
 
+/// State shared by every task executed in a single run.
+#[derive(Default)]
+struct RunState {
+    executed: HashSet<String>,
+    timings: Vec<(String, Duration)>,
+}
+
 fn execute_task(
     name: &str,
     ctx: &Context,
     extra_args: &[String],
-    executed: &mut HashSet<String>,
+    state: &mut RunState,
 ) -> Result<(), EvalError> {
-    if executed.contains(name) {
+    if state.executed.contains(name) {
         return Ok(());
     }
     let task = ctx.tasks.get(name).ok_or_else(|| EvalError::Other {
@@ -1228,8 +2632,36 @@ fn execute_task(
         line: 0,
     })?;
     for step in &task.steps {
-        execute_task(step, ctx, extra_args, executed)?;
+        execute_task(step, ctx, extra_args, state)?;
+    }
+    for hook in &task.pre {
+        execute_task(hook, ctx, extra_args, state)?;
+    }
+    let mut result = run_command(name, task, ctx, extra_args, state);
+    // Post hooks always run, like a `finally`. If the command already failed,
+    // hook failures are only reported so they don't mask the original error.
+    for hook in &task.post {
+        if let Err(e) = execute_task(hook, ctx, extra_args, state) {
+            if result.is_ok() {
+                result = Err(e);
+            } else {
+                eprintln!("Post hook '{}' of task '{}' failed: {}", hook, name, e);
+            }
+        }
     }
+    result?;
+    state.executed.insert(name.to_string());
+    Ok(())
+}
+
+/// Build the interpolated command line of a task, along with the variables
+/// used to interpolate it.
+fn task_command(
+    name: &str,
+    task: &Task,
+    ctx: &Context,
+    extra_args: &[String],
+) -> Result<(String, HashMap<String, String>), EvalError> {
     let mut cmd_line = if let Some(shell_cmd) = &task.shell {
         shell_cmd.clone()
     } else if let Some(cmd_tpl) = &task.cmd {
@@ -1241,103 +2673,362 @@ fn execute_task(
     } else {
         return Err(EvalError::Other {
             message: format!("Task '{}' has no command to execute", name),
-            line: 0,
+            line: task.line,
         });
     };
+    // Typed params consume their arguments; the rest are appended to the command.
+    let params = bind_params(name, task, ctx, extra_args)?;
+    let extra_args = &extra_args[params.len()..];
     if !extra_args.is_empty() {
         let extra = extra_args.join(" ");
         cmd_line = format!("{} {}", cmd_line, extra);
     }
-    let mut interp_env = ctx.defs.clone();
-    interp_env.extend(task.props.clone());
+    let interp_env = task_env(task, ctx, params);
     let cmd_line = interpolate(
         &cmd_line,
         &interp_env,
-        task.props
-            .get("line")
-            .and_then(|l| l.parse().ok())
-            .unwrap_or(0),
+        &ctx.env_vars,
+        ctx.config.as_ref(),
+        ctx.max_interp_depth,
+        task.line,
     )?;
+    Ok((cmd_line, interp_env))
+}
+
+/// The variables available to a task's command: defs, then the task's own
+/// properties, then its bound params.
+fn task_env(task: &Task, ctx: &Context, params: Vec<(String, String)>) -> HashMap<String, String> {
+    let mut interp_env = ctx.defs.clone();
+    interp_env.extend(task.props.clone());
+    interp_env.extend(params);
+    interp_env
+}
+
+/// Interpolate the task's `log` path, if it has one.
+fn task_log_path(
+    name: &str,
+    task: &Task,
+    ctx: &Context,
+    interp_env: &HashMap<String, String>,
+) -> Result<Option<String>, EvalError> {
+    let Some(log) = &task.log else {
+        return Ok(None);
+    };
+    // `{name}` is the task name unless a variable of that name exists.
+    let mut log_env = interp_env.clone();
+    log_env
+        .entry("name".to_string())
+        .or_insert_with(|| name.to_string());
+    interpolate(
+        log,
+        &log_env,
+        &ctx.env_vars,
+        ctx.config.as_ref(),
+        ctx.max_interp_depth,
+        task.line,
+    )
+    .map(Some)
+}
+
+/// Bind the task's typed params to the leading extra args, checking each value
+/// against its type.
+fn bind_params(
+    name: &str,
+    task: &Task,
+    ctx: &Context,
+    extra_args: &[String],
+) -> Result<Vec<(String, String)>, EvalError> {
+    let mut bound = Vec::new();
+    for (i, (param, ty)) in task.param_types.iter().enumerate() {
+        let Some(value) = extra_args.get(i) else {
+            return Err(EvalError::Other {
+                message: format!(
+                    "Task '{}' is missing parameter '{}' ({}); pass it after `--`",
+                    name, param, ty
+                ),
+                line: task.line,
+            });
+        };
+        let valid = match ty.as_str() {
+            "int" => value.parse::<i64>().is_ok(),
+            "float" => value.parse::<f64>().is_ok(),
+            "str" => true,
+            _ => match ctx.types.get(ty) {
+                Some(allowed) if allowed.contains(value) => true,
+                Some(allowed) => {
+                    return Err(EvalError::TypeError {
+                        var: param.clone(),
+                        value: value.clone(),
+                        allowed: allowed.clone(),
+                        line: task.line,
+                    })
+                }
+                None => {
+                    return Err(EvalError::Other {
+                        message: format!("Unknown type '{}' for parameter '{}'", ty, param),
+                        line: task.line,
+                    })
+                }
+            },
+        };
+        if !valid {
+            return Err(EvalError::Other {
+                message: format!(
+                    "Parameter '{}' of task '{}' expects {}, got '{}'",
+                    param, name, ty, value
+                ),
+                line: task.line,
+            });
+        }
+        bound.push((param.clone(), value.clone()));
+    }
+    Ok(bound)
+}
 
-    println!("Executing task {}:", name);
-    if let Some(desc) = &task.desc {
-        println!("  Description: {}", desc);
+/// Build, interpolate and run the command of a single task.
+fn run_command(
+    name: &str,
+    task: &Task,
+    ctx: &Context,
+    extra_args: &[String],
+    state: &mut RunState,
+) -> Result<(), EvalError> {
+    let (cmd_line, interp_env) = task_command(name, task, ctx, extra_args)?;
+
+    // Status output goes to stderr so stdout only has the command's output.
+    if !ctx.quiet {
+        eprintln!("Executing task {}:", ctx.paint(name, Color::Bold));
+        if let Some(desc) = &task.desc {
+            eprintln!("  Description: {}", desc);
+        }
+        if !task.meta.is_empty() {
+            eprintln!("  Metadata: {:?}", task.meta);
+        }
+        if ctx.verbose {
+            eprintln!("  Variables:");
+            let mut vars: Vec<_> = interp_env.iter().collect();
+            vars.sort();
+            for (key, value) in vars {
+                eprintln!("    {} = {}", key, value);
+            }
+        }
+        eprintln!("  Command: {}", ctx.paint(&cmd_line, Color::Cyan));
     }
-    if !task.meta.is_empty() {
-        println!("  Metadata: {:?}", task.meta);
+
+    let log_path = task_log_path(name, task, ctx, &interp_env)?;
+    if let Some(path) = &log_path {
+        if !ctx.quiet {
+            eprintln!("  Log: {}", path);
+        }
     }
-    println!("  Command: {}", cmd_line);
 
-    let status = Command::new("sh")
-        .arg("-c")
-        .arg(&cmd_line)
-        .status()
-        .map_err(|e| EvalError::ExecutionError {
-            message: e.to_string(),
-            line: 0,
-        })?;
+    let start = Instant::now();
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(&cmd_line).envs(&ctx.env_vars);
+    let status = match &log_path {
+        Some(path) => run_logged(&mut command, Path::new(path), ctx.tee),
+        None => command.status(),
+    }
+    .map_err(|e| EvalError::ExecutionError {
+        message: e.to_string(),
+        line: 0,
+        code: None,
+    })?;
+    state.timings.push((name.to_string(), start.elapsed()));
     if !status.success() {
+        if !ctx.quiet {
+            eprintln!(
+                "  {}",
+                ctx.paint(&format!("Failed: {}", status), Color::Red)
+            );
+        }
         return Err(EvalError::ExecutionError {
             message: format!("Task '{}' exited with status {}", name, status),
             line: 0,
+            code: status.code(),
         });
     }
-    executed.insert(name.to_string());
+    if !ctx.quiet {
+        eprintln!("  {}", ctx.paint("Done", Color::Green));
+    }
     Ok(())
 }
 
-// ======================================================================
-// Main function
-// ======================================================================
-
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+/// Run a command with its stdout and stderr written to `log`, creating the file
+/// and its parent directories. With `tee`, the output also goes to the terminal.
+fn run_logged(command: &mut Command, log: &Path, tee: bool) -> io::Result<ExitStatus> {
+    if let Some(parent) = log.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = fs::File::create(log)?;
+    if !tee {
+        return command.stdout(file.try_clone()?).stderr(file).status();
+    }
 
-    let path = Path::new(&cli.file);
-    let dsl_content = fs::read_to_string(path)
-        .map_err(|e| format!("Error reading DSL file {}: {}", cli.file, e))?;
-    let forms = loads_all(&dsl_content).map_err(|e| format!("Parse error: {}", e))?;
-    let mut ctx = Context::new();
-    process_forms(&forms, &mut ctx)?;
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let file = Mutex::new(file);
+    // Copy each stream line by line so the two don't interleave mid-line in the log.
+    fn copy(
+        reader: impl io::Read,
+        mut terminal: impl Write,
+        file: &Mutex<fs::File>,
+    ) -> io::Result<()> {
+        for line in io::BufReader::new(reader).split(b'\n') {
+            let mut line = line?;
+            line.push(b'\n');
+            terminal.write_all(&line)?;
+            file.lock().unwrap().write_all(&line)?;
+        }
+        Ok(())
+    }
+    thread::scope(|scope| {
+        let out = scope.spawn(|| copy(stdout, io::stdout(), &file));
+        let err = scope.spawn(|| copy(stderr, io::stderr(), &file));
+        out.join().expect("stdout copy panicked")?;
+        err.join().expect("stderr copy panicked")
+    })?;
+    child.wait()
+}
 
-    if cli.list {
-        println!("Available tasks:");
-        let mut names: Vec<_> = ctx.tasks.keys().collect();
-        names.sort();
-        for name in names {
-            if let Some(task) = ctx.tasks.get(name) {
-                if cli.verbose {
-                    println!(
-                        "  {}: {}",
-                        task.name,
-                        task.desc.as_deref().unwrap_or(&task.title)
-                    );
-                } else {
-                    println!("  {}", task.name);
-                }
+/// Validate every task without running anything, returning all problems found:
+/// commands and log paths that fail to interpolate, references to unknown tasks,
+/// and cycles between tasks.
+fn check_tasks(ctx: &Context) -> Vec<EvalError> {
+    let mut errors = Vec::new();
+    if let Some((default, line)) = &ctx.default_task {
+        if !ctx.tasks.contains_key(default) && !ctx.groups.contains_key(default) {
+            errors.push(EvalError::Other {
+                message: format!("Default task '{}' does not exist", default),
+                line: *line,
+            });
+        }
+    }
+    let mut names: Vec<_> = ctx.tasks.keys().collect();
+    names.sort();
+    for name in &names {
+        let task = &ctx.tasks[*name];
+        // Stand in a valid value for each typed param so the command can be checked.
+        let samples: Vec<_> = task
+            .param_types
+            .iter()
+            .map(|(_, ty)| match ty.as_str() {
+                "int" | "float" => "0".to_string(),
+                _ => ctx
+                    .types
+                    .get(ty)
+                    .and_then(|allowed| allowed.first().cloned())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        errors.extend(task_command(name, task, ctx, &samples).err());
+        let params = bind_params(name, task, ctx, &samples).unwrap_or_default();
+        let interp_env = task_env(task, ctx, params);
+        errors.extend(task_log_path(name, task, ctx, &interp_env).err());
+        for dep in task.steps.iter().chain(&task.pre).chain(&task.post) {
+            if !ctx.tasks.contains_key(dep) {
+                errors.push(EvalError::Other {
+                    message: format!("Task '{}' depends on unknown task '{}'", name, dep),
+                    line: task.line,
+                });
             }
         }
-        return Ok(());
     }
+    let mut group_names: Vec<_> = ctx.groups.keys().collect();
+    group_names.sort();
+    for name in group_names {
+        let group = &ctx.groups[name];
+        for dep in group.steps.iter().chain(&group.default_task) {
+            if !ctx.tasks.contains_key(dep) {
+                errors.push(EvalError::Other {
+                    message: format!("Group '{}' refers to unknown task '{}'", name, dep),
+                    line: group.line,
+                });
+            }
+        }
+    }
+    let mut visited = HashSet::new();
+    for name in names {
+        let mut path = Vec::new();
+        if let Some(cycle) = find_cycle(name, ctx, &mut visited, &mut path) {
+            errors.push(EvalError::Other {
+                message: format!("Dependency cycle: {}", cycle.join(" -> ")),
+                line: ctx.tasks[name].line,
+            });
+        }
+    }
+    errors
+}
 
-    // If no tasks are specified, default to "default"
-    let tasks_to_run = if cli.tasks.is_empty() {
-        vec!["default".to_string()]
-    } else {
-        cli.tasks
-    };
+/// Depth-first search for a cycle through `steps`, `pre` and `post`, returning
+/// the tasks in the cycle with the first one repeated at the end.
+fn find_cycle<'a>(
+    name: &'a str,
+    ctx: &'a Context,
+    visited: &mut HashSet<&'a str>,
+    path: &mut Vec<&'a str>,
+) -> Option<Vec<String>> {
+    if let Some(pos) = path.iter().position(|n| *n == name) {
+        let mut cycle: Vec<_> = path[pos..].iter().map(|n| n.to_string()).collect();
+        cycle.push(name.to_string());
+        return Some(cycle);
+    }
+    if !visited.insert(name) {
+        return None;
+    }
+    let task = ctx.tasks.get(name)?;
+    path.push(name);
+    for dep in task.steps.iter().chain(&task.pre).chain(&task.post) {
+        if let Some(cycle) = find_cycle(dep, ctx, visited, path) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    None
+}
 
-    let mut executed = HashSet::new();
+/// Run the given tasks or groups in order.
+fn run_tasks(
+    tasks_to_run: &[String],
+    ctx: &Context,
+    extra_args: &[String],
+    state: &mut RunState,
+) -> Result<(), EvalError> {
     for tname in tasks_to_run {
-        if let Some(group) = ctx.groups.get(&tname) {
-            println!("Group {}:", tname);
-            if let Some(desc) = &group.desc {
-                println!("  Description: {}", desc);
+        if let Some(group) = ctx.groups.get(tname) {
+            if !ctx.quiet {
+                eprintln!("Group {}:", ctx.paint(tname, Color::Bold));
+                if let Some(desc) = &group.desc {
+                    eprintln!("  Description: {}", desc);
+                }
+                if !group.meta.is_empty() {
+                    eprintln!("  Metadata: {:?}", group.meta);
+                }
             }
-            if !group.meta.is_empty() {
-                println!("  Metadata: {:?}", group.meta);
+            if let Some(default_task) = &group.default_task {
+                for step in &group.steps {
+                    execute_task(step, ctx, extra_args, state)?;
+                }
+                execute_task(default_task, ctx, extra_args, state)?;
+                continue;
             }
             let prefix = format!("{}.", tname);
+            // Prerequisites of this group and its nested groups run before any member.
+            let mut groups: Vec<_> = ctx
+                .groups
+                .iter()
+                .filter(|(k, _)| *k == tname || k.starts_with(&prefix))
+                .collect();
+            groups.sort_by_key(|(k, _)| *k);
+            for (_, g) in groups {
+                for step in &g.steps {
+                    execute_task(step, ctx, extra_args, state)?;
+                }
+            }
             let mut keys: Vec<_> = ctx
                 .tasks
                 .keys()
@@ -1346,10 +3037,10 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .collect();
             keys.sort();
             for key in keys {
-                execute_task(&key, &ctx, &cli.extra_args, &mut executed)?;
+                execute_task(&key, ctx, extra_args, state)?;
             }
-        } else if ctx.tasks.contains_key(&tname) {
-            execute_task(&tname, &ctx, &cli.extra_args, &mut executed)?;
+        } else if ctx.tasks.contains_key(tname) {
+            execute_task(tname, ctx, extra_args, state)?;
         } else {
             let prefix = format!("{}.", tname);
             let mut keys: Vec<_> = ctx
@@ -1363,10 +3054,677 @@ fn main() -> Result<(), Box<dyn Error>> {
             } else {
                 keys.sort();
                 for key in keys {
-                    execute_task(&key, &ctx, &cli.extra_args, &mut executed)?;
+                    execute_task(&key, ctx, extra_args, state)?;
                 }
             }
         }
     }
     Ok(())
 }
+
+/// Print a table with the duration of each executed task and the total run time.
+fn print_timings(timings: &[(String, Duration)], total: Duration) {
+    let width = timings
+        .iter()
+        .map(|(name, _)| name.len())
+        .chain(["Total".len()])
+        .max()
+        .unwrap_or(0);
+    eprintln!("Timings:");
+    for (name, duration) in timings {
+        eprintln!("  {:width$}  {:>8.2}s", name, duration.as_secs_f64());
+    }
+    eprintln!("  {:width$}  {:>8.2}s", "Total", total.as_secs_f64());
+}
+
+// ======================================================================
+// Dependency graph export (Graphviz dot)
+// ======================================================================
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn dot_node(task: &Task) -> String {
+    format!(
+        "\"{}\" [label=\"{}\\n{}\"];",
+        dot_escape(&task.name),
+        dot_escape(&task.name),
+        dot_escape(&task.title)
+    )
+}
+
+/// Return the innermost group containing `name`, if any.
+fn owning_group<'a>(name: &str, group_names: &[&'a String]) -> Option<&'a String> {
+    group_names
+        .iter()
+        .filter(|g| name.starts_with(&format!("{}.", g)))
+        .max_by_key(|g| g.len())
+        .copied()
+}
+
+/// Render a group as a cluster with its own tasks and nested groups.
+fn dot_cluster(
+    group_name: &String,
+    ctx: &Context,
+    names: &[&String],
+    group_names: &[&String],
+    depth: usize,
+    lines: &mut Vec<String>,
+) {
+    let indent = "  ".repeat(depth);
+    lines.push(format!(
+        "{}subgraph \"cluster_{}\" {{",
+        indent,
+        dot_escape(group_name)
+    ));
+    lines.push(format!(
+        "{}  label=\"{}\\n{}\";",
+        indent,
+        dot_escape(group_name),
+        dot_escape(&ctx.groups[group_name].title)
+    ));
+    for name in names {
+        if owning_group(name, group_names) == Some(group_name) {
+            lines.push(format!("{}  {}", indent, dot_node(&ctx.tasks[*name])));
+        }
+    }
+    for child in group_names {
+        if owning_group(child, group_names) == Some(group_name) {
+            dot_cluster(child, ctx, names, group_names, depth + 1, lines);
+        }
+    }
+    lines.push(format!("{}}}", indent));
+}
+
+/// Render the tasks as a Graphviz digraph, with an edge from each task to its
+/// steps and each group drawn as a cluster around its member tasks.
+fn dependency_graph(ctx: &Context) -> String {
+    let mut names: Vec<_> = ctx.tasks.keys().collect();
+    names.sort();
+    let mut group_names: Vec<_> = ctx.groups.keys().collect();
+    group_names.sort();
+
+    let mut lines = vec!["digraph tasks {".to_string()];
+    for group_name in &group_names {
+        if owning_group(group_name, &group_names).is_none() {
+            dot_cluster(group_name, ctx, &names, &group_names, 1, &mut lines);
+        }
+    }
+    for name in &names {
+        if owning_group(name, &group_names).is_none() {
+            lines.push(format!("  {}", dot_node(&ctx.tasks[*name])));
+        }
+    }
+    for name in &names {
+        for step in &ctx.tasks[*name].steps {
+            lines.push(format!(
+                "  \"{}\" -> \"{}\";",
+                dot_escape(name),
+                dot_escape(step)
+            ));
+        }
+    }
+    lines.push("}".to_string());
+    lines.join("\n")
+}
+
+// ======================================================================
+// Task listing and selection
+// ======================================================================
+
+/// Describe every task as a JSON object, for editors and completion scripts.
+fn task_list_json(ctx: &Context) -> String {
+    let group_names: Vec<_> = ctx.groups.keys().collect();
+    let mut names: Vec<_> = ctx.tasks.keys().collect();
+    names.sort();
+    let tasks: Vec<_> = names
+        .into_iter()
+        .map(|name| {
+            let task = &ctx.tasks[name];
+            // Tags come from `(meta (tags "a, b"))`.
+            let tags: Vec<_> = task
+                .meta
+                .get("tags")
+                .map(|t| {
+                    t.split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|t| !t.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default();
+            serde_json::json!({
+                "name": task.name,
+                "title": task.title,
+                "desc": task.desc,
+                "tags": tags,
+                "group": owning_group(name, &group_names),
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&tasks).expect("task list is valid JSON")
+}
+
+/// Show a fuzzy-searchable list of tasks and return the chosen one, or `None` if
+/// the user cancelled.
+fn pick_task(ctx: &Context, default_task: &str) -> io::Result<Option<String>> {
+    let mut names: Vec<_> = ctx.tasks.keys().collect();
+    names.sort();
+    let items: Vec<_> = names
+        .iter()
+        .map(|name| format!("{} - {}", name, ctx.tasks[*name].title))
+        .collect();
+    let default = names.iter().position(|n| *n == default_task).unwrap_or(0);
+    dialoguer::FuzzySelect::new()
+        .with_prompt("Task to run")
+        .items(&items)
+        .default(default)
+        .interact_opt()
+        .map(|choice| choice.map(|i| names[i].clone()))
+        .map_err(|dialoguer::Error::IO(e)| e)
+}
+
+// ======================================================================
+// Main function
+// ======================================================================
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+
+    let path = Path::new(&cli.file);
+    let dsl_content = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading DSL file {}: {}", cli.file, e))?;
+    let forms = loads_all_collect(&dsl_content).map_err(|errors| {
+        for e in &errors {
+            eprintln!("Parse error: {}", e);
+        }
+        format!("Found {} parse error(s)", errors.len())
+    })?;
+    let mut ctx = Context::new();
+    ctx.isolated_env = cli.isolated_env;
+    ctx.max_interp_depth = cli.max_interp_depth;
+    ctx.color = cli.color.enabled();
+    ctx.quiet = cli.quiet;
+    ctx.verbose = cli.verbose;
+    ctx.trace = cli.trace;
+    ctx.tee = cli.tee;
+    if let Some(seed) = cli.seed {
+        ctx.rng = RefCell::new(StdRng::seed_from_u64(seed));
+    }
+    // `--check` keeps going after an error, so every problem is reported.
+    let load_errors = if cli.check {
+        process_forms_collect(&forms, &mut ctx)
+    } else {
+        // Print with Display so hints like type suggestions reach the user.
+        if let Err(e) = process_forms(&forms, &mut ctx) {
+            eprintln!("{}", e);
+            return Err(format!("Failed to load {}", cli.file).into());
+        }
+        Vec::new()
+    };
+
+    if cli.warn_unused {
+        for name in unused_defs(&forms, &ctx) {
+            eprintln!("Warning: def '{}' is never used", name);
+        }
+    }
+
+    if cli.list && cli.json {
+        println!("{}", task_list_json(&ctx));
+        return Ok(());
+    }
+
+    if cli.list {
+        println!("Available tasks:");
+        let mut names: Vec<_> = ctx.tasks.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(task) = ctx.tasks.get(name) {
+                if cli.verbose {
+                    let params: Vec<_> = task
+                        .param_types
+                        .iter()
+                        .map(|(param, ty)| format!("{}: {}", param, ty))
+                        .collect();
+                    let params = if params.is_empty() {
+                        String::new()
+                    } else {
+                        format!(" ({})", params.join(", "))
+                    };
+                    println!(
+                        "  {}: {}{}",
+                        task.name,
+                        task.desc.as_deref().unwrap_or(&task.title),
+                        params
+                    );
+                } else {
+                    println!("  {}", task.name);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    if cli.check {
+        let mut errors = load_errors;
+        errors.extend(check_tasks(&ctx));
+        for e in &errors {
+            eprintln!("{}", e);
+        }
+        if !errors.is_empty() {
+            return Err(format!("Found {} problem(s) in {}", errors.len(), cli.file).into());
+        }
+        println!("No problems found in {}", cli.file);
+        return Ok(());
+    }
+
+    if cli.graph {
+        println!("{}", dependency_graph(&ctx));
+        return Ok(());
+    }
+
+    // If no tasks are specified, let the user pick one when attached to a terminal.
+    // Otherwise, run the `(default ...)` task, or else "default".
+    let default_task = ctx
+        .default_task
+        .clone()
+        .map_or_else(|| "default".to_string(), |(name, _)| name);
+    let interactive = !cli.no_interactive
+        && cli.only.is_empty()
+        && io::stdin().is_terminal()
+        && io::stderr().is_terminal();
+    let tasks_to_run = if cli.tasks.is_empty() && interactive {
+        match pick_task(&ctx, &default_task)? {
+            Some(name) => vec![name],
+            None => return Ok(()),
+        }
+    } else if cli.tasks.is_empty() {
+        vec![default_task]
+    } else {
+        cli.tasks
+    };
+
+    for name in cli.skip.iter().chain(&cli.only) {
+        if !ctx.tasks.contains_key(name) {
+            return Err(format!("Unknown task '{}'", name).into());
+        }
+    }
+
+    let mut state = RunState::default();
+    state.executed.extend(cli.skip);
+    let start = Instant::now();
+    let result = if cli.only.is_empty() {
+        run_tasks(&tasks_to_run, &ctx, &cli.extra_args, &mut state)
+    } else {
+        cli.only.iter().try_for_each(|name| {
+            run_command(name, &ctx.tasks[name], &ctx, &cli.extra_args, &mut state)
+        })
+    };
+    if cli.timings {
+        print_timings(&state.timings, start.elapsed());
+    }
+    // Exit with the failed command's own code so callers can tell failures apart.
+    if let Err(
+        e @ EvalError::ExecutionError {
+            code: Some(code), ..
+        },
+    ) = result
+    {
+        eprintln!("Error: {}", e);
+        process::exit(code);
+    }
+    result?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse `src` as the only element of a list, print it back, and check that
+    /// the printed form parses to the same expression.
+    fn round_trip(src: &str) -> String {
+        let forms = loads_all(&format!("({})", src)).unwrap();
+        let printed = forms[0].to_string();
+        assert_eq!(loads_all(&printed).unwrap(), forms);
+        printed
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn interp(s: &str, env: &HashMap<String, String>) -> Result<String, EvalError> {
+        interpolate(s, env, &HashMap::new(), None, 10, 1)
+    }
+
+    #[test]
+    fn interpolate_default_unused_when_present() {
+        let env = vars(&[("model", "gpt")]);
+        assert_eq!(interp("run {model:-base}", &env).unwrap(), "run gpt");
+    }
+
+    #[test]
+    fn interpolate_default_when_absent() {
+        assert_eq!(interp("run {model:-base}", &vars(&[])).unwrap(), "run base");
+        assert_eq!(interp("run{model:-}", &vars(&[])).unwrap(), "run");
+    }
+
+    #[test]
+    fn interpolate_absent_without_default_errors() {
+        let err = interp("run {model}", &vars(&[])).unwrap_err();
+        assert!(matches!(err, EvalError::UndefinedVariable { line: 1, .. }));
+    }
+
+    /// Load `src` into a fresh context.
+    fn load(src: &str) -> Context {
+        let mut ctx = Context::new();
+        process_forms(&loads_all(src).unwrap(), &mut ctx).unwrap();
+        ctx
+    }
+
+    /// The interpolated command of `task` in `src`.
+    fn command(src: &str, task: &str) -> Result<String, EvalError> {
+        let ctx = load(src);
+        task_command(task, &ctx.tasks[task], &ctx, &[]).map(|(cmd, _)| cmd)
+    }
+
+    #[test]
+    fn interpolate_brace_escapes() {
+        let env = vars(&[("x", "1"), ("b", "2")]);
+        assert_eq!(interp("echo {{a}} {b}", &env).unwrap(), "echo {a} 2");
+        assert_eq!(interp("{{{x}}}", &env).unwrap(), "{1}");
+        assert_eq!(interp("}} {x} {{", &env).unwrap(), "} 1 {");
+    }
+
+    #[test]
+    fn def_with_brace_escapes_stays_literal() {
+        let src = r#"
+(def (x "1") (json "{{\"a\": {x}}}"))
+(def (wrapped "[{json}]"))
+(task show "Show" (shell "echo '{json}' {wrapped} {x}"))
+"#;
+        assert_eq!(
+            command(src, "show").unwrap(),
+            r#"echo '{"a": 1}' [{"a": 1}] 1"#
+        );
+    }
+
+    #[test]
+    fn check_collects_every_problem_with_its_line() {
+        let src = r#"(default missing)
+(def (a "{nope}")
+     (b "ok"))
+(def (c "{alsonope}"))
+(task build "Build" (cmd "make {b}") (log "logs/{what}.log"))
+"#;
+        let mut ctx = Context::new();
+        let mut errors = process_forms_collect(&loads_all(src).unwrap(), &mut ctx);
+        errors.extend(check_tasks(&ctx));
+        let messages: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(messages.len(), 4, "{:?}", messages);
+        assert!(messages[0].contains("nope") && messages[0].ends_with("(at line 2)"));
+        assert!(messages[1].contains("alsonope") && messages[1].ends_with("(at line 4)"));
+        assert!(messages[2].contains("missing") && messages[2].ends_with("(at line 1)"));
+        assert!(messages[3].contains("what") && messages[3].ends_with("(at line 5)"));
+        // Entries after a bad one in the same `def` are still defined.
+        assert_eq!(ctx.defs["b"], "ok");
+    }
+
+    /// A command that appends a line to a fresh counter file and prints how many
+    /// times it has run, followed by `exit`.
+    fn counter_command(exit: &str) -> (String, std::path::PathBuf) {
+        let path = env::temp_dir().join(format!("sexp-rs-counter-{}", uuid::Uuid::new_v4()));
+        let cmd = format!(
+            "echo x >> '{0}'; wc -l < '{0}'; exit {1}",
+            path.display(),
+            exit
+        );
+        (cmd, path)
+    }
+
+    fn run_count(path: &Path) -> usize {
+        let count = fs::read_to_string(path).unwrap().lines().count();
+        fs::remove_file(path).unwrap();
+        count
+    }
+
+    #[test]
+    fn shell_output_is_cached() {
+        let (cmd, path) = counter_command("0");
+        let src = format!(
+            r#"(def (a (shell "{0}")) (b (shell "{0}")) (c (from-shell "{0}")))"#,
+            cmd
+        );
+        let ctx = load(&src);
+        assert_eq!(run_count(&path), 1);
+        assert_eq!((&*ctx.defs["a"], &*ctx.defs["b"]), ("1", "1"));
+    }
+
+    #[test]
+    fn shell_nocache_always_runs() {
+        let (cmd, path) = counter_command("0");
+        let src = format!(
+            r#"(def (a (shell "{0}")) (b (shell-nocache "{0}")) (c (shell "{0}")))"#,
+            cmd
+        );
+        let ctx = load(&src);
+        assert_eq!(run_count(&path), 2);
+        assert_eq!((&*ctx.defs["b"], &*ctx.defs["c"]), ("2", "1"));
+    }
+
+    #[test]
+    fn failed_shell_output_is_not_cached() {
+        let (cmd, path) = counter_command("1");
+        let src = format!(r#"(def (a (shell "{0}")) (b (shell "{0}")))"#, cmd);
+        let ctx = load(&src);
+        assert_eq!(run_count(&path), 2);
+        assert_eq!(ctx.defs["b"], "2");
+    }
+
+    #[test]
+    fn interpolate_env_vars() {
+        let scoped = vars(&[("SEXP_RS_TEST_VAR", "scoped")]);
+        let interp = |s: &str| interpolate(s, &vars(&[]), &scoped, None, 10, 1);
+        assert_eq!(interp("a $SEXP_RS_TEST_VAR b").unwrap(), "a scoped b");
+        assert_eq!(interp("${SEXP_RS_TEST_VAR}x").unwrap(), "scopedx");
+        assert_eq!(interp("${SEXP_RS_TEST_UNSET:-dflt}").unwrap(), "dflt");
+        assert_eq!(interp("echo $$ $$HOME").unwrap(), "echo $$ $$HOME");
+        assert!(matches!(
+            interp("${SEXP_RS_TEST_UNSET}"),
+            Err(EvalError::UndefinedVariable { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn undefined_bare_env_var_is_an_error() {
+        let src = r#"
+(task loop "Loop" (shell "for f in x y; do echo $f; done"))"#;
+        assert!(matches!(
+            command(src, "loop"),
+            Err(EvalError::UndefinedVariable { line: 2, .. })
+        ));
+        let src = r#"
+(def (d "$"))
+(task loop "Loop" (shell "for f in x y; do echo {d}f; done"))"#;
+        assert_eq!(
+            command(src, "loop").unwrap(),
+            "for f in x y; do echo $f; done"
+        );
+    }
+
+    /// Evaluate a single expression in an empty context.
+    fn eval(src: &str) -> Result<Value, EvalError> {
+        let ctx = Context::new();
+        eval_expr(&loads_all(src).unwrap()[0], &HashMap::new(), &ctx)
+    }
+
+    fn eval_string(src: &str) -> String {
+        eval(src).unwrap().as_str().unwrap().into_owned()
+    }
+
+    #[test]
+    fn equal_ci_ignores_case() {
+        assert_eq!(eval_string(r#"(equal-ci? "Prod" "prod")"#), "true");
+        assert_eq!(eval_string(r#"(equal-ci? " PROD " "prod")"#), "true");
+        assert_eq!(eval_string(r#"(equal-ci? "prod" "dev")"#), "false");
+        // `equal?` stays case-sensitive.
+        assert_eq!(eval_string(r#"(equal? "Prod" "prod")"#), "false");
+    }
+
+    fn eval_list_of(src: &str) -> Vec<String> {
+        match eval(src).unwrap() {
+            Value::List(items) => items,
+            other => panic!("expected a list, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn map_applies_quoted_builtin() {
+        assert_eq!(eval_list_of("(map 'upper '(a bc))"), ["A", "BC"]);
+        assert_eq!(eval_list_of("(map 'lower '())"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn map_binds_element_to_underscore() {
+        assert_eq!(
+            eval_list_of(r#"(map "{_}.txt" '(a b))"#),
+            ["a.txt", "b.txt"]
+        );
+        assert_eq!(
+            eval_list_of(r#"(map (upper "x-{_}") '(a b))"#),
+            ["X-A", "X-B"]
+        );
+        // The element is inserted literally, even if it has braces.
+        assert_eq!(eval_list_of(r#"(map "<{_}>" '("{a}"))"#), ["<{a}>"]);
+    }
+
+    #[test]
+    fn map_drops_none_results() {
+        assert_eq!(
+            eval_list_of(r#"(map (when (equal? _ "b") _) '(a b c))"#),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn map_rejects_invalid_operations() {
+        assert!(eval("(map '(upper) '(a))").is_err());
+        assert!(eval("(map 'upper \"a\")").is_err());
+    }
+
+    #[test]
+    fn close_paren_after_whitespace_and_comments() {
+        let forms = loads_all("(a b\n  ; done\n )\n(c )").unwrap();
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms[0].to_string(), "(a b)");
+        assert_eq!(forms[1].to_string(), "(c)");
+    }
+
+    #[test]
+    fn comments_attach_to_nodes() {
+        let forms = loads_all_with_comments("; top\n(a ; after a\n ; before b\n b)").unwrap();
+        let comment = |text: &str, trailing, line| {
+            let text = text.to_string();
+            SExp::Comment(Comment { text, trailing }, line)
+        };
+        assert_eq!(forms[0], comment("; top", false, 1));
+        let SExp::List(items, _) = &forms[1] else {
+            panic!("expected a list");
+        };
+        assert_eq!(items[1], comment("; after a", true, 2));
+        assert_eq!(items[2], comment("; before b", false, 3));
+        assert_eq!(items[3].to_string(), "b");
+    }
+
+    #[test]
+    fn pretty_print_with_comments_round_trips() {
+        let src = r#"; Settings
+(def (name "say \"hi\"") ; greeting
+     (n 3))
+
+;; Build
+(task build "Build"
+  ; what to run
+  (cmd "make {name}")
+  '(a b) ; quoted
+  nil
+  ; trailing note
+  )
+"#;
+        let forms = loads_all_with_comments(src).unwrap();
+        let pretty = dumps_pretty_all(&forms, "  ");
+        for comment in [
+            "; Settings",
+            "; greeting",
+            ";; Build",
+            "; what to run",
+            "; quoted",
+            "; trailing note",
+        ] {
+            assert!(
+                pretty.contains(comment),
+                "missing {comment:?} in:\n{pretty}"
+            );
+        }
+        // Printing again gives the same text, and the code is unchanged.
+        let reparsed = loads_all_with_comments(&pretty).unwrap();
+        assert_eq!(dumps_pretty_all(&reparsed, "  "), pretty);
+        let code = |s: &str| -> Vec<String> {
+            loads_all(s)
+                .unwrap()
+                .iter()
+                .map(|f| f.to_string())
+                .collect()
+        };
+        assert_eq!(code(&pretty), code(src));
+    }
+
+    #[test]
+    fn split_on_separator() {
+        assert_eq!(eval_list_of(r#"(split "," "a,b,,c")"#), ["a", "b", "", "c"]);
+        assert_eq!(eval_list_of(r#"(split ", " "a")"#), ["a"]);
+    }
+
+    #[test]
+    fn split_on_empty_separator_gives_characters() {
+        assert_eq!(eval_list_of(r#"(split "" "abc")"#), ["a", "b", "c"]);
+        assert_eq!(eval_list_of(r#"(split "" "")"#), Vec::<String>::new());
+    }
+
+    #[test]
+    fn replace_every_occurrence() {
+        assert_eq!(eval_string(r#"(replace "-" "_" "a-b-c")"#), "a_b_c");
+        assert_eq!(eval_string(r#"(replace "-" "" "a-b")"#), "ab");
+        assert!(eval(r#"(replace "" "x" "abc")"#).is_err());
+    }
+
+    #[test]
+    fn numbers_round_trip() {
+        assert_eq!(round_trip("42"), "(42)");
+        assert_eq!(round_trip("-7"), "(-7)");
+        assert_eq!(round_trip("1.5"), "(1.5)");
+        assert_eq!(round_trip("1.0"), "(1)");
+        assert_eq!(round_trip("-0.0"), "(0)");
+        assert_eq!(round_trip("inf nan"), "(inf nan)");
+    }
+
+    #[test]
+    fn large_integers_keep_precision() {
+        assert_eq!(round_trip("9007199254740993"), "(9007199254740993)");
+        assert_eq!(round_trip("9223372036854775807"), "(9223372036854775807)");
+        assert_ne!(Number::Int(9007199254740993), Number::Int(9007199254740992));
+    }
+
+    #[test]
+    fn numbers_compare_across_kinds() {
+        assert_eq!(Number::Int(1), Number::Float(1.0));
+        assert_eq!(Value::Str("1.0".to_string()).as_num(), Some(Number::Int(1)));
+        assert_eq!(
+            format_num(Value::Str(" 3 ".to_string()).as_num().unwrap()),
+            "3"
+        );
+    }
+}
@@ -1,23 +1,135 @@
 //! Pretty print git blame output
 
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
-use std::io::{self, BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{self, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::OnceLock;
 
-use clap::Parser;
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::{ArgAction, Parser, ValueEnum};
 use regex::Regex;
+use serde_json::json;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-/// Show pretty-printed git blame for file
+/// Show pretty-printed git blame for files
 #[derive(Parser, Debug)]
 #[command(arg_required_else_help = true)]
 pub struct Args {
-    /// File to get blame
-    pub file: PathBuf,
+    /// Files to get blame, each shown under a header when there are several (files that can't
+    /// be blamed are then skipped, and the exit status is non-zero)
+    #[arg(required = true)]
+    pub files: Vec<PathBuf>,
+
+    /// Blame the file as of this revision (commit, branch or tag) instead of the working tree
+    #[arg(long)]
+    pub rev: Option<String>,
+
+    /// Print the lines as a JSON array of objects with the fields `file`, `hash` (full commit
+    /// hash), `author`, `email`, `date`, `summary`, `lineno` (a number) and `code`, without
+    /// a pager
+    #[arg(long)]
+    pub json: bool,
+
+    /// Print how many lines each author owns across the files, with percentages, instead of
+    /// the lines
+    #[arg(long, conflicts_with = "json")]
+    pub stats: bool,
+
+    /// Number of columns between tab stops when expanding tabs in the code
+    #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub tabwidth: u16,
 
     /// Disable pager and print directly to stdout
     #[arg(long)]
     pub no_pager: bool,
+
+    /// Only blame the lines in START,END (END can also be +N or -N lines from START)
+    #[arg(short = 'L', long, value_name = "START,END", value_parser = parse_line_range)]
+    pub lines: Option<String>,
+
+    /// Ignore whitespace changes when finding the commit that last changed each line
+    #[arg(short = 'w', long)]
+    pub ignore_whitespace: bool,
+
+    /// Ignore the commits listed in this file, like `git blame --ignore-revs-file` (default:
+    /// `.git-blame-ignore-revs` at the repository root, if it exists)
+    #[arg(long, value_name = "PATH")]
+    pub ignore_revs_file: Option<PathBuf>,
+
+    /// Detect lines moved or copied from other files, like `git blame -C` (repeat up to
+    /// three times to search more commits)
+    #[arg(short = 'C', long, action = ArgAction::Count)]
+    pub copies: u8,
+
+    /// Syntax-highlight the code based on the file's extension (when colors are enabled)
+    #[cfg(feature = "highlight")]
+    #[arg(long)]
+    pub highlight: bool,
+
+    /// Show how long ago each line was changed (e.g. "3 months ago") instead of the date
+    #[arg(long)]
+    pub relative_date: bool,
+
+    /// Show the committer's name, email and date instead of the author's (other options
+    /// referring to the author, including the JSON fields, then use the committer)
+    #[arg(long)]
+    pub committer: bool,
+
+    /// Show the author's email instead of their name
+    #[arg(long, conflicts_with = "email_column")]
+    pub email: bool,
+
+    /// Show the author's email in a column after their name
+    #[arg(long)]
+    pub email_column: bool,
+
+    /// Only show lines whose author contains NAME (case-insensitive)
+    #[arg(long, value_name = "NAME")]
+    pub author: Option<String>,
+
+    /// Give each author a distinct color, derived from their name
+    #[arg(long)]
+    pub author_colors: bool,
+
+    /// When to color the output (auto: if stdout is a terminal and NO_COLOR is unset)
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+}
+
+/// Check that a line range is in the `START,END` form `git blame -L` takes.
+fn parse_line_range(range: &str) -> Result<String, String> {
+    let invalid =
+        || format!("invalid line range '{range}': expected START,END (e.g. 10,20 or 10,+5)");
+    let (start, end) = range.split_once(',').ok_or_else(invalid)?;
+    let start: usize = start.parse().map_err(|_| invalid())?;
+    let end_offset = end.strip_prefix('+').or_else(|| end.strip_prefix('-'));
+    let end: usize = end_offset.unwrap_or(end).parse().map_err(|_| invalid())?;
+    if start == 0 || (end_offset.is_none() && end < start) {
+        return Err(invalid());
+    }
+    Ok(range.to_string())
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()) && io::stdout().is_terminal()
+            }
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -25,38 +137,153 @@ enum Color {
     Red,
     Green,
     Yellow,
+    Blue,
     Magenta,
+    Cyan,
+    Bold,
+    /// A color from the 256-color palette
+    Indexed(u8),
     Reset,
 }
 
 impl Color {
-    fn code(&self) -> &str {
+    fn code(&self) -> Cow<'static, str> {
         match self {
-            Color::Red => "\x1b[31m",
-            Color::Green => "\x1b[32m",
-            Color::Yellow => "\x1b[33m",
-            Color::Magenta => "\x1b[35m",
-            Color::Reset => "\x1b[0m",
+            Color::Red => "\x1b[31m".into(),
+            Color::Green => "\x1b[32m".into(),
+            Color::Yellow => "\x1b[33m".into(),
+            Color::Blue => "\x1b[34m".into(),
+            Color::Magenta => "\x1b[35m".into(),
+            Color::Cyan => "\x1b[36m".into(),
+            Color::Bold => "\x1b[1m".into(),
+            Color::Indexed(index) => format!("\x1b[38;5;{index}m").into(),
+            Color::Reset => "\x1b[0m".into(),
         }
     }
+
+    /// A color for the author that is the same on every run, picked from the colors of the
+    /// 256-color cube that are bright enough to read and not gray.
+    fn for_author(author: &str) -> Color {
+        // FNV-1a, since std's hashers aren't guaranteed to be stable across releases.
+        let hash = author.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+        static PALETTE: OnceLock<Vec<u8>> = OnceLock::new();
+        let palette = PALETTE.get_or_init(|| {
+            (16..=231)
+                .filter(|&index| {
+                    let (r, g, b) = ((index - 16) / 36, (index - 16) / 6 % 6, (index - 16) % 6);
+                    r + g + b >= 6 && !(r == g && g == b)
+                })
+                .collect()
+        });
+        Color::Indexed(palette[(hash % palette.len() as u64) as usize])
+    }
 }
 
+/// How to render the output.
+#[derive(Clone, Copy)]
+struct Style {
+    enabled: bool,
+    author_colors: bool,
+    tab_width: usize,
+}
+
+/// The author git gives to lines with local changes.
+const UNCOMMITTED_AUTHOR: &str = "Not Committed Yet";
+
 struct Entry {
+    file: PathBuf,
+    hash: String,
     short_hash: String,
     author: String,
+    email: String,
+    date: String,
     summary: String,
     lineno: String,
     code_line: String,
 }
 
-fn prettify(text: &str, width: usize, color: Color) -> String {
-    format!(
-        "{}{:width$}{}",
-        color.code(),
-        text,
-        Color::Reset.code(),
-        width = width
-    )
+/// The `.git-blame-ignore-revs` of the repository containing `file`, if it has one.
+fn default_ignore_revs_file(file: &Path) -> Option<PathBuf> {
+    // At a past revision, the file's directory may no longer exist either.
+    let dir = file
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())
+        .unwrap_or(Path::new("."));
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    let root = String::from_utf8_lossy(&output.stdout);
+    let path = PathBuf::from(root.trim()).join(".git-blame-ignore-revs");
+    path.is_file().then_some(path)
+}
+
+/// Format the `author-time` (Unix seconds) and `author-tz` (e.g. `+0200`) porcelain values
+/// as the date in the author's time zone.
+fn format_date(time: &str, tz: &str) -> Option<String> {
+    let seconds: i64 = time.parse().ok()?;
+    let (sign, offset) = match (tz.strip_prefix('+'), tz.strip_prefix('-')) {
+        (Some(offset), _) => (1, offset),
+        (_, Some(offset)) => (-1, offset),
+        _ => return None,
+    };
+    let hours: i32 = offset.get(..2)?.parse().ok()?;
+    let minutes: i32 = offset.get(2..)?.parse().ok()?;
+    let offset = FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))?;
+    let date = DateTime::from_timestamp(seconds, 0)?.with_timezone(&offset);
+    Some(date.format("%Y-%m-%d").to_string())
+}
+
+/// The longest prefix of `text` that fits in `width` terminal columns.
+fn truncate_to_width(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, c) in text.char_indices() {
+        used += c.width().unwrap_or(0);
+        if used > width {
+            return &text[..i];
+        }
+    }
+    text
+}
+
+/// Format the `author-time` (Unix seconds) porcelain value as the time since then, in the
+/// largest unit that fits, as in "3 months ago".
+fn format_relative_date(time: &str, now: i64) -> Option<String> {
+    const MINUTE: i64 = 60;
+    const HOUR: i64 = 60 * MINUTE;
+    const DAY: i64 = 24 * HOUR;
+    const WEEK: i64 = 7 * DAY;
+    const MONTH: i64 = 30 * DAY;
+    const YEAR: i64 = 365 * DAY;
+
+    let seconds: i64 = time.parse().ok()?;
+    let ago = (now - seconds).max(0);
+    let (count, unit) = match ago {
+        _ if ago < MINUTE => (ago, "second"),
+        _ if ago < HOUR => (ago / MINUTE, "minute"),
+        _ if ago < DAY => (ago / HOUR, "hour"),
+        _ if ago < 2 * WEEK => (ago / DAY, "day"),
+        _ if ago < 2 * MONTH => (ago / WEEK, "week"),
+        _ if ago < YEAR => (ago / MONTH, "month"),
+        _ => (ago / YEAR, "year"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    Some(format!("{count} {unit}{plural} ago"))
+}
+
+/// Pad `text` to `width` terminal columns, which `{:width$}` doesn't do for wide characters.
+fn prettify(text: &str, width: usize, color: Color, style: Style) -> String {
+    let padding = " ".repeat(width.saturating_sub(text.width()));
+    if !style.enabled {
+        return format!("{text}{padding}");
+    }
+    format!("{}{text}{padding}{}", color.code(), Color::Reset.code())
 }
 
 macro_rules! die {
@@ -67,19 +294,184 @@ macro_rules! die {
 }
 
 pub fn run(args: &Args) {
-    if !args.file.exists() {
-        die!("File does not exist");
+    let style = Style {
+        enabled: args.color.enabled(),
+        author_colors: args.author_colors,
+        tab_width: args.tabwidth.into(),
+    };
+    let many_files = args.files.len() > 1;
+
+    let mut total_lines = 0;
+    let mut files = Vec::new();
+    let mut failed = false;
+    for file in &args.files {
+        let mut entries = match blame_file(args, file) {
+            Ok(entries) => entries,
+            Err(message) if !many_files => die!("{message}"),
+            Err(message) => {
+                eprintln!("{message}");
+                failed = true;
+                continue;
+            }
+        };
+
+        // Highlight before filtering, since the highlighter's state carries across lines.
+        #[cfg(feature = "highlight")]
+        if args.highlight && style.enabled && !args.json {
+            highlight_code(file, &mut entries);
+        }
+
+        total_lines += entries.len();
+        if entries.is_empty() && !args.json {
+            if many_files {
+                eprintln!("{}: No blame information (empty file)", file.display());
+            } else {
+                eprintln!("No blame information (empty file)");
+            }
+            continue;
+        }
+        if let Some(name) = &args.author {
+            let name = name.to_lowercase();
+            entries.retain(|entry| entry.author.to_lowercase().contains(&name));
+        }
+        files.push((file, entries));
     }
 
-    let output = match Command::new("git")
-        .args(["blame", "--line-porcelain"])
-        .arg(&args.file)
-        .output()
+    print_files(args, files, style, many_files, total_lines);
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Print the blamed files in the format the arguments ask for.
+fn print_files(
+    args: &Args,
+    files: Vec<(&PathBuf, Vec<Entry>)>,
+    style: Style,
+    many_files: bool,
+    total_lines: usize,
+) {
+    if args.json {
+        print_json(files.iter().flat_map(|(_, entries)| entries));
+        return;
+    }
+
+    if args.stats {
+        let entries: Vec<_> = files.iter().flat_map(|(_, entries)| entries).collect();
+        print_stats(&entries, style);
+        return;
+    }
+
+    let mut max_widths = vec![("short_hash", 10), ("author", 20)];
+    if args.email_column {
+        max_widths.push(("email", 30));
+    }
+    max_widths.extend([("date", 14), ("summary", 50), ("lineno", 6)]);
+
+    let matched_lines: usize = files.iter().map(|(_, entries)| entries.len()).sum();
+    let blocks: Vec<Block> = files
+        .into_iter()
+        .map(|(file, entries)| Block::new(file, entries, &max_widths))
+        .collect();
+
+    if blocks.is_empty() {
+        return;
+    }
+    if args.no_pager {
+        print_to_stdout(&blocks, many_files, style);
+    } else {
+        print_to_pager(&blocks, many_files, style);
+    }
+
+    if let Some(name) = &args.author {
+        eprintln!("{matched_lines} of {total_lines} lines matched author '{name}'");
+    }
+}
+
+/// Whether the file looks binary: like git, check for a NUL byte in its first 8000 bytes.
+/// With `rev`, the file is checked as of that revision. Files that can't be read are assumed
+/// to be text, and left for `git blame` to report.
+fn is_binary(file: &Path, rev: Option<&str>) -> bool {
+    const LIMIT: u64 = 8000;
+    let mut head = Vec::with_capacity(LIMIT as usize);
+    let read = match rev {
+        Some(rev) => {
+            // Run git next to the file, so `./` resolves the path whether it's absolute or not.
+            let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty());
+            let Some(name) = file.file_name() else {
+                return false;
+            };
+            let Ok(mut child) = Command::new("git")
+                .current_dir(dir.unwrap_or(Path::new(".")))
+                .arg("cat-file")
+                .arg("blob")
+                .arg(format!("{rev}:./{}", name.to_string_lossy()))
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+            else {
+                return false;
+            };
+            let stdout = child.stdout.take().expect("stdout is piped");
+            let read = stdout.take(LIMIT).read_to_end(&mut head);
+            // Git may still be writing a large file, so stop it rather than wait.
+            let _ = child.kill();
+            let _ = child.wait();
+            read
+        }
+        None => match std::fs::File::open(file) {
+            Ok(file) => file.take(LIMIT).read_to_end(&mut head),
+            Err(_) => return false,
+        },
+    };
+    read.is_ok() && head.contains(&0)
+}
+
+/// Run `git blame` on the file and parse its porcelain output, or describe why it failed.
+fn blame_file(args: &Args, file: &Path) -> Result<Vec<Entry>, String> {
+    // At a past revision, the file may no longer exist in the working tree.
+    if args.rev.is_none() && !file.exists() {
+        return Err(format!("{}: File does not exist", file.display()));
+    }
+    if is_binary(file, args.rev.as_deref()) {
+        return Err(format!(
+            "{}: Binary file, not showing blame",
+            file.display()
+        ));
+    }
+
+    let mut command = Command::new("git");
+    command.args(["blame", "--line-porcelain"]);
+    if args.ignore_whitespace {
+        command.arg("-w");
+    }
+    for _ in 0..args.copies.min(3) {
+        command.arg("-C");
+    }
+    if let Some(lines) = &args.lines {
+        command.args(["-L", lines]);
+    }
+    if let Some(path) = args
+        .ignore_revs_file
+        .clone()
+        .or_else(|| default_ignore_revs_file(file))
     {
-        Ok(output) if !output.status.success() => die!(
-            "Error running git blame:\n{}",
-            String::from_utf8_lossy(&output.stderr)
-        ),
+        command.arg("--ignore-revs-file").arg(path);
+    }
+    if let Some(rev) = &args.rev {
+        command.arg(rev);
+    }
+    let output = match command.arg("--").arg(file).output() {
+        Ok(output) if !output.status.success() => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(match &args.rev {
+                _ if !stderr.contains("no such path") => {
+                    format!("{}: Error running git blame:\n{stderr}", file.display())
+                }
+                Some(rev) => format!("{}: Not in revision {rev}", file.display()),
+                None => format!("{}: Not tracked by git", file.display()),
+            });
+        }
         Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
         Err(e) => die!("Failed to execute git: {e}"),
     };
@@ -89,6 +481,17 @@ pub fn run(args: &Args) {
 
     let mut entries = Vec::new();
     let mut i = 0;
+    let now = Utc::now().timestamp();
+
+    let who = if args.committer {
+        "committer"
+    } else {
+        "author"
+    };
+    let name_prefix = format!("{who} ");
+    let mail_prefix = format!("{who}-mail ");
+    let time_prefix = format!("{who}-time ");
+    let tz_prefix = format!("{who}-tz ");
 
     while i < lines.len() {
         let line = lines[i];
@@ -98,16 +501,29 @@ pub fn run(args: &Args) {
         }
 
         let parts: Vec<&str> = line.split_whitespace().collect();
+        let hash = parts[0].to_string();
         let short_hash = parts[0][0..8].to_string();
         let lineno = parts[2].to_string();
         let mut author = String::new();
+        let mut email = String::new();
+        let mut author_time = String::new();
+        let mut author_tz = String::new();
         let mut summary = String::new();
 
         i += 1;
         while i < lines.len() && !lines[i].starts_with('\t') && !hash_regex.is_match(lines[i]) {
             let line = lines[i];
-            if let Some(author_str) = line.strip_prefix("author ") {
+            if let Some(author_str) = line.strip_prefix(&name_prefix) {
                 author = author_str.to_string();
+            } else if let Some(mail_str) = line.strip_prefix(&mail_prefix) {
+                email = mail_str
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string();
+            } else if let Some(time_str) = line.strip_prefix(&time_prefix) {
+                author_time = time_str.to_string();
+            } else if let Some(tz_str) = line.strip_prefix(&tz_prefix) {
+                author_tz = tz_str.to_string();
             } else if let Some(summary_str) = line.strip_prefix("summary ") {
                 summary = summary_str.to_string();
             }
@@ -119,9 +535,24 @@ pub fn run(args: &Args) {
             code_line = lines[i][1..].to_string();
         }
 
+        let date = if args.relative_date {
+            format_relative_date(&author_time, now)
+        } else {
+            format_date(&author_time, &author_tz)
+        }
+        .unwrap_or_default();
+
+        if args.email {
+            author.clone_from(&email);
+        }
+
         entries.push(Entry {
+            file: file.to_path_buf(),
+            hash,
             short_hash,
             author,
+            email,
+            date,
             summary,
             lineno,
             code_line,
@@ -129,51 +560,144 @@ pub fn run(args: &Args) {
         i += 1;
     }
 
-    let max_widths = [
-        ("short_hash", 10),
-        ("author", 20),
-        ("summary", 50),
-        ("lineno", 6),
-    ];
+    Ok(entries)
+}
 
-    let field_lengths: Vec<(_, usize)> = max_widths
-        .iter()
-        .map(|&(field, max_width)| {
-            let max_len = entries
-                .iter()
-                .map(|e| match field {
-                    "short_hash" => e.short_hash.len(),
-                    "author" => e.author.len(),
-                    "summary" => e.summary.len(),
-                    "lineno" => e.lineno.len(),
-                    _ => die!("Invalid field in git blame: {field}."),
-                })
-                .max()
-                .unwrap_or(0);
-            (field, max_len.min(max_width))
-        })
-        .collect();
+/// A file's entries, with the width of each column.
+struct Block<'a> {
+    file: &'a Path,
+    entries: Vec<Entry>,
+    field_lengths: Vec<(&'static str, usize)>,
+}
 
-    // Truncate fields if necessary
-    for entry in &mut entries {
-        for (field, max_width) in max_widths {
-            let value = match field {
-                "author" => &mut entry.author,
-                "summary" => &mut entry.summary,
-                _ => continue,
-            };
-            if value.len() > max_width {
-                value.truncate(max_width - 1);
-                value.push('…');
+impl<'a> Block<'a> {
+    /// Size the columns to the file's entries, truncating values longer than the maximum
+    /// width of their column.
+    fn new(file: &'a Path, mut entries: Vec<Entry>, max_widths: &[(&'static str, usize)]) -> Self {
+        let field_lengths = max_widths
+            .iter()
+            .map(|&(field, max_width)| {
+                let max_len = entries
+                    .iter()
+                    .map(|e| match field {
+                        "short_hash" => e.short_hash.width(),
+                        "author" => e.author.width(),
+                        "email" => e.email.width(),
+                        "date" => e.date.width(),
+                        "summary" => e.summary.width(),
+                        "lineno" => e.lineno.width(),
+                        _ => die!("Invalid field in git blame: {field}."),
+                    })
+                    .max()
+                    .unwrap_or(0);
+                (field, max_len.min(max_width))
+            })
+            .collect();
+
+        // Truncate fields if necessary
+        for entry in &mut entries {
+            for &(field, max_width) in max_widths {
+                let value = match field {
+                    "author" => &mut entry.author,
+                    "email" => &mut entry.email,
+                    "summary" => &mut entry.summary,
+                    _ => continue,
+                };
+                if value.width() > max_width {
+                    *value = format!("{}…", truncate_to_width(value, max_width - 1));
+                }
             }
         }
+
+        Block {
+            file,
+            entries,
+            field_lengths,
+        }
     }
+}
 
-    if args.no_pager {
-        print_to_stdout(&entries, &field_lengths);
-    } else {
-        print_to_pager(&entries, &field_lengths);
+/// Replace each entry's code with its syntax-highlighted version, leaving it as plain text
+/// if the file's syntax is unknown.
+#[cfg(feature = "highlight")]
+fn highlight_code(file: &std::path::Path, entries: &mut [Entry]) {
+    use syntect::easy::HighlightLines;
+    use syntect::highlighting::ThemeSet;
+    use syntect::parsing::SyntaxSet;
+    use syntect::util::as_24_bit_terminal_escaped;
+
+    let syntax_set = SyntaxSet::load_defaults_newlines();
+    let Some(syntax) = file
+        .extension()
+        .and_then(|ext| syntax_set.find_syntax_by_extension(&ext.to_string_lossy()))
+    else {
+        return;
+    };
+    let themes = ThemeSet::load_defaults();
+    let mut highlighter = HighlightLines::new(syntax, &themes.themes["base16-ocean.dark"]);
+
+    for entry in entries {
+        let line = format!("{}\n", entry.code_line);
+        if let Ok(ranges) = highlighter.highlight_line(&line, &syntax_set) {
+            let highlighted = as_24_bit_terminal_escaped(&ranges, false);
+            entry.code_line = format!(
+                "{}{}",
+                highlighted.trim_end_matches('\n'),
+                Color::Reset.code()
+            );
+        }
+    }
+}
+
+/// Replace tabs with spaces up to the next tab stop, counting columns from the start of the
+/// code and skipping the ANSI escape sequences added by highlighting.
+fn expand_tabs(code: &str, tab_width: usize) -> String {
+    let mut expanded = String::with_capacity(code.len());
+    let mut column = 0;
+    let mut chars = code.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\t' => {
+                let spaces = tab_width - column % tab_width;
+                expanded.extend(std::iter::repeat(' ').take(spaces));
+                column += spaces;
+            }
+            '\x1b' => {
+                expanded.push(c);
+                for c in chars.by_ref() {
+                    expanded.push(c);
+                    if c == 'm' {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                expanded.push(c);
+                column += c.width().unwrap_or(0);
+            }
+        }
     }
+    expanded
+}
+
+/// Write each file's lines, under a header if there are several files
+fn write_blocks<W: Write>(
+    mut writer: W,
+    blocks: &[Block],
+    headers: bool,
+    style: Style,
+) -> io::Result<()> {
+    for (i, block) in blocks.iter().enumerate() {
+        if headers {
+            if i > 0 {
+                writeln!(writer)?;
+            }
+            let header = format!("==> {} <==", block.file.display());
+            writeln!(writer, "{}", prettify(&header, 0, Color::Bold, style))?;
+        }
+        write_entries(&mut writer, &block.entries, &block.field_lengths, style)?;
+    }
+    writer.flush()
 }
 
 /// Write formatted lines to a writer
@@ -181,10 +705,13 @@ fn write_entries<W: Write>(
     mut writer: W,
     entries: &[Entry],
     field_lengths: &[(&str, usize)],
+    style: Style,
 ) -> io::Result<()> {
     let colors = [
         ("short_hash", Color::Red),
         ("author", Color::Green),
+        ("email", Color::Green),
+        ("date", Color::Blue),
         ("summary", Color::Yellow),
         ("lineno", Color::Magenta),
     ];
@@ -192,41 +719,99 @@ fn write_entries<W: Write>(
     for entry in entries {
         let formatted = colors
             .iter()
-            .map(|&(field, color)| {
+            .filter_map(|&(field, color)| {
+                // Optional columns are only in `field_lengths` when shown.
                 let width = field_lengths
                     .iter()
                     .find(|&&(f, _)| f == field)
-                    .map(|&(_, w)| w)
-                    .unwrap();
+                    .map(|&(_, w)| w)?;
                 let value = match field {
                     "short_hash" => &entry.short_hash,
                     "author" => &entry.author,
+                    "email" => &entry.email,
+                    "date" => &entry.date,
                     "summary" => &entry.summary,
                     "lineno" => &entry.lineno,
                     _ => unreachable!(),
                 };
-                prettify(value, width, color)
+                let color = match field {
+                    "author" if entry.author == UNCOMMITTED_AUTHOR => Color::Cyan,
+                    "author" if style.author_colors => Color::for_author(&entry.author),
+                    _ => color,
+                };
+                Some(prettify(value, width, color, style))
             })
             .collect::<Vec<String>>()
             .join(" ");
 
-        let formatted_line = format!("{} {}", formatted, entry.code_line);
+        let code = expand_tabs(&entry.code_line, style.tab_width);
+        let formatted_line = format!("{} {}", formatted, code);
         writeln!(writer, "{formatted_line}")?;
     }
 
     Ok(())
 }
 
-fn print_to_stdout(entries: &[Entry], field_lengths: &[(&str, usize)]) {
+/// Print each author's line count and share of the lines, largest first.
+fn print_stats(entries: &[&Entry], style: Style) {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for entry in entries {
+        *counts.entry(&entry.author).or_default() += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by(|(a_author, a_count), (b_author, b_count)| {
+        b_count.cmp(a_count).then(a_author.cmp(b_author))
+    });
+
+    let author_width = counts.iter().map(|(a, _)| a.width()).max().unwrap_or(0);
+    let count_width = entries.len().to_string().len();
+    for (author, count) in counts {
+        let color = match author {
+            UNCOMMITTED_AUTHOR => Color::Cyan,
+            _ if style.author_colors => Color::for_author(author),
+            _ => Color::Green,
+        };
+        println!(
+            "{} {:>count_width$} {:5.1}%",
+            prettify(author, author_width, color, style),
+            count,
+            count as f64 / entries.len() as f64 * 100.0,
+        );
+    }
+}
+
+fn print_json<'a>(entries: impl Iterator<Item = &'a Entry>) {
+    let entries: Vec<_> = entries
+        .map(|entry| {
+            json!({
+                "file": entry.file,
+                "hash": entry.hash,
+                "author": entry.author,
+                "email": entry.email,
+                "date": entry.date,
+                "summary": entry.summary,
+                "lineno": entry.lineno.parse::<u64>().ok(),
+                "code": entry.code_line,
+            })
+        })
+        .collect();
+
+    match serde_json::to_string_pretty(&entries) {
+        Ok(output) => println!("{output}"),
+        Err(e) => die!("Failed to serialize blame to JSON: {e}"),
+    }
+}
+
+fn print_to_stdout(blocks: &[Block], headers: bool, style: Style) {
     let stdout = io::stdout();
     let writer = BufWriter::new(stdout.lock());
 
-    if let Err(e) = write_entries(writer, entries, field_lengths) {
+    if let Err(e) = write_blocks(writer, blocks, headers, style) {
         die!("Failed to write to stdout: {e}");
     }
 }
 
-fn print_to_pager(entries: &[Entry], field_lengths: &[(&str, usize)]) {
+fn print_to_pager(blocks: &[Block], headers: bool, style: Style) {
     // Get pager command from PAGER env var, defaulting to "less"
     let pager_cmd = env::var("PAGER").unwrap_or_else(|_| "less".to_string());
 
@@ -248,7 +833,7 @@ fn print_to_pager(entries: &[Entry], field_lengths: &[(&str, usize)]) {
     let pager_stdin = pager.stdin.take().expect("Failed to open pager stdin");
     let writer = BufWriter::new(pager_stdin);
 
-    if let Err(e) = write_entries(writer, entries, field_lengths) {
+    if let Err(e) = write_blocks(writer, blocks, headers, style) {
         die!("Failed to write to pager: {e}");
     }
 
@@ -261,3 +846,146 @@ fn print_to_pager(entries: &[Entry], field_lengths: &[(&str, usize)]) {
         Err(e) => eprintln!("Warning: Failed to wait for pager: {e}"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAIN: Style = Style {
+        enabled: false,
+        author_colors: false,
+        tab_width: 4,
+    };
+
+    fn entry(author: &str, summary: &str, code_line: &str) -> Entry {
+        Entry {
+            file: PathBuf::from("file.rs"),
+            hash: "0123456789abcdef0123456789abcdef01234567".to_string(),
+            short_hash: "01234567".to_string(),
+            author: author.to_string(),
+            email: String::new(),
+            date: "2024-01-02".to_string(),
+            summary: summary.to_string(),
+            lineno: "1".to_string(),
+            code_line: code_line.to_string(),
+        }
+    }
+
+    fn render(entries: Vec<Entry>) -> Vec<String> {
+        let max_widths = [
+            ("short_hash", 10),
+            ("author", 20),
+            ("date", 14),
+            ("summary", 50),
+            ("lineno", 6),
+        ];
+        let block = Block::new(Path::new("file.rs"), entries, &max_widths);
+        let mut output = Vec::new();
+        write_entries(&mut output, &block.entries, &block.field_lengths, PLAIN).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Run git in `dir`, panicking if it fails.
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {args:?} failed");
+    }
+
+    #[test]
+    fn binary_fixture_is_detected() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        assert!(is_binary(&testdata.join("binary.bin"), None));
+        assert!(!is_binary(Path::new(file!()), None));
+        assert!(!is_binary(&testdata.join("missing.bin"), None));
+    }
+
+    #[test]
+    fn binary_check_uses_the_file_at_rev() {
+        let repo = env::temp_dir().join(format!("trem-blame-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&repo);
+        std::fs::create_dir_all(&repo).unwrap();
+        let file = repo.join("data");
+        std::fs::write(&file, b"\x00\x01\x02binary").unwrap();
+        git(&repo, &["init", "-q"]);
+        git(&repo, &["add", "data"]);
+        git(&repo, &["commit", "-q", "-m", "Add binary data"]);
+        std::fs::write(&file, "now text\n").unwrap();
+
+        assert!(!is_binary(&file, None));
+        assert!(is_binary(&file, Some("HEAD")));
+        assert!(!is_binary(&repo.join("missing"), Some("HEAD")));
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn ignore_revs_file_comes_from_the_file_repository() {
+        let repo = env::temp_dir().join(format!("trem-ignore-revs-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&repo);
+        std::fs::create_dir_all(repo.join("src")).unwrap();
+        git(&repo, &["init", "-q"]);
+        let ignore_revs = repo.join(".git-blame-ignore-revs");
+        std::fs::write(&ignore_revs, "").unwrap();
+        let found =
+            |file: &Path| default_ignore_revs_file(file).map(|path| path.canonicalize().unwrap());
+
+        let expected = Some(ignore_revs.canonicalize().unwrap());
+        assert_eq!(found(&repo.join("src/main.rs")), expected);
+        assert_eq!(found(&repo.join("gone/old.rs")), expected);
+        std::fs::remove_file(&ignore_revs).unwrap();
+        assert_eq!(found(&repo.join("src/main.rs")), None);
+        std::fs::remove_dir_all(&repo).unwrap();
+    }
+
+    #[test]
+    fn truncate_to_width_counts_wide_characters_as_two_columns() {
+        assert_eq!(truncate_to_width("山田太郎", 5), "山田");
+        assert_eq!(truncate_to_width("山田太郎", 8), "山田太郎");
+        assert_eq!(truncate_to_width("abc", 2), "ab");
+    }
+
+    #[test]
+    fn long_summary_ends_with_a_single_ellipsis() {
+        let summary = "Refactor the parser so that errors point at the offending token instead";
+        let block = Block::new(
+            Path::new("file.rs"),
+            vec![entry("Bob", summary, "code")],
+            &[("summary", 50)],
+        );
+        let truncated = &block.entries[0].summary;
+        assert_eq!(truncated.width(), 50);
+        assert!(truncated.ends_with('…'));
+        assert_eq!(truncated.matches('…').count(), 1);
+        assert!(summary.starts_with(truncated.trim_end_matches('…')));
+    }
+
+    #[test]
+    fn cjk_author_is_truncated_and_columns_align() {
+        let lines = render(vec![
+            entry("山田太郎山田太郎山田太郎", "Init", "first"),
+            entry("Bob", "Init", "second"),
+        ]);
+        let author = lines[0].split(' ').nth(1).unwrap();
+        assert_eq!(author, "山田太郎山田太郎山…");
+        assert_eq!(author.width(), 19);
+
+        // The code starts at the same column on every line.
+        let code_columns: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                let code = line.rfind(' ').unwrap() + 1;
+                line[..code].width()
+            })
+            .collect();
+        assert_eq!(code_columns[0], code_columns[1]);
+    }
+}
@@ -1,6 +1,7 @@
 //! Run a shell command for each string in a JSON array
 use std::io::{self, Read, Write};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use anyhow::{Context, Result};
@@ -9,27 +10,87 @@ use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use serde_json::Value;
 
+use crate::jhead;
+
 #[derive(Parser, Debug)]
 #[command(arg_required_else_help = true)]
 pub struct Args {
-    /// Command to run on each string
+    /// Command to run on each string. Every `{}` in it is replaced with the string;
+    /// without one, the string is written to the command's stdin instead
     #[clap(required = true)]
     pub command: Vec<String>,
+
+    /// JSON file to read the array from (`.gz` and `.zst` files are decompressed), or `-`
+    /// for stdin
+    #[arg(short, long, default_value = "-")]
+    pub input: String,
+
+    /// Also process elements that aren't strings, as their compact JSON text (e.g. `42` or
+    /// `{"a":1}`); strings are still passed without quotes
+    #[arg(long)]
+    pub stringify: bool,
+
+    /// Fail if the array has elements that aren't strings, instead of warning and skipping
+    /// them (without --stringify)
+    #[arg(long, conflicts_with = "stringify")]
+    pub strict: bool,
+
+    /// Number of commands to run at once (0 means the number of CPUs; 1 runs them in order)
+    #[arg(short, long, default_value_t = 0)]
+    pub jobs: usize,
+
+    /// Capture each command's stderr, and use it as the result of commands that fail
+    /// (it is dropped for commands that succeed)
+    #[arg(long, conflicts_with = "quiet_stderr")]
+    pub capture_stderr: bool,
+
+    /// Discard the commands' stderr instead of showing it
+    #[arg(long)]
+    pub quiet_stderr: bool,
+
+    /// Stop starting new commands after the first one fails (the others still finish)
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Print the outputs as a JSON array of strings instead of one per line (invalid UTF-8
+    /// is replaced with U+FFFD)
+    #[arg(long)]
+    pub json: bool,
 }
 
 pub fn run(args: &Args) -> Result<()> {
     let mut buffer = String::new();
-    io::stdin()
+    jhead::open_input(&args.input)?
         .read_to_string(&mut buffer)
-        .context("Failed to read from stdin")?;
+        .with_context(|| format!("Failed to read from {}", args.input))?;
 
     let data: Value = serde_json::from_str(&buffer).context("Failed to parse JSON")?;
-    let strings = match data {
-        Value::Array(arr) => arr
+    let Value::Array(arr) = data else {
+        anyhow::bail!("Input must be a JSON array");
+    };
+    let strings = if args.stringify {
+        arr.iter()
+            .map(|v| match v {
+                Value::String(s) => s.clone(),
+                _ => v.to_string(),
+            })
+            .collect::<Vec<String>>()
+    } else {
+        let strings = arr
             .iter()
             .filter_map(|v| v.as_str().map(String::from))
-            .collect::<Vec<String>>(),
-        _ => anyhow::bail!("Input must be a JSON array"),
+            .collect::<Vec<String>>();
+        let dropped = arr.len() - strings.len();
+        if dropped > 0 {
+            if args.strict {
+                anyhow::bail!("{dropped} of {} elements are not strings", arr.len());
+            }
+            eprintln!(
+                "Warning: skipping {dropped} of {} elements that are not strings (use --stringify to process them)",
+                arr.len()
+            );
+        }
+        strings
     };
 
     let pb = Arc::new(Mutex::new(
@@ -38,40 +99,113 @@ pub fn run(args: &Args) -> Result<()> {
         ).context("Failed to create progress bar style")?),
     ));
 
-    let cmd = args.command[0].clone();
-    let cmd_args: Vec<_> = args.command[1..].to_vec();
-
-    // Process strings in parallel but print in order
-    let results: Vec<_> = strings
-        .into_par_iter()
-        .map(|text| {
-            // Run the command
-            let output = Command::new(&cmd)
-                .args(&cmd_args)
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .and_then(|mut child| {
-                    if let Some(mut stdin) = child.stdin.take() {
-                        stdin.write_all(text.as_bytes())?;
-                    }
-                    child.wait_with_output()
-                });
-
-            let result = match output {
-                Ok(output) => String::from_utf8_lossy(&output.stdout).trim().to_string(),
-                Err(e) => format!("Error: {}", e),
-            };
-
-            pb.lock().unwrap().inc(1);
-
-            result
-        })
+    let uses_placeholder = args.command.iter().any(|arg| arg.contains("{}"));
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs)
+        .build()
+        .context("Failed to create thread pool")?;
+
+    let total = strings.len();
+    let any_failed = AtomicBool::new(false);
+
+    // Process strings in parallel but print in order. Each result is the output and whether
+    // the command succeeded, or None if it was skipped by --fail-fast.
+    let results: Vec<Option<(String, bool)>> = pool.install(|| {
+        strings
+            .into_par_iter()
+            .map(|text| {
+                if args.fail_fast && any_failed.load(Ordering::Relaxed) {
+                    pb.lock().unwrap().inc(1);
+                    return None;
+                }
+
+                // Run the command
+                let command: Vec<_> = if uses_placeholder {
+                    args.command
+                        .iter()
+                        .map(|arg| arg.replace("{}", &text))
+                        .collect()
+                } else {
+                    args.command.clone()
+                };
+                let stdin = if uses_placeholder {
+                    Stdio::null()
+                } else {
+                    Stdio::piped()
+                };
+                let stderr = if args.capture_stderr {
+                    Stdio::piped()
+                } else if args.quiet_stderr {
+                    Stdio::null()
+                } else {
+                    Stdio::inherit()
+                };
+                let output = Command::new(&command[0])
+                    .args(&command[1..])
+                    .stdin(stdin)
+                    .stdout(Stdio::piped())
+                    .stderr(stderr)
+                    .spawn()
+                    .and_then(|mut child| {
+                        if let Some(mut stdin) = child.stdin.take() {
+                            // Commands that exit without reading their input still succeed.
+                            match stdin.write_all(text.as_bytes()) {
+                                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                                _ => {}
+                            }
+                        }
+                        child.wait_with_output()
+                    });
+
+                let result = match output {
+                    Ok(output) if args.capture_stderr && !output.status.success() => (
+                        format!(
+                            "Error: {}: {}",
+                            output.status,
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        ),
+                        false,
+                    ),
+                    Ok(output) => (
+                        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+                        output.status.success(),
+                    ),
+                    Err(e) => (format!("Error: {}", e), false),
+                };
+                if !result.1 {
+                    any_failed.store(true, Ordering::Relaxed);
+                }
+
+                pb.lock().unwrap().inc(1);
+
+                Some(result)
+            })
+            .collect()
+    });
+
+    let skipped = results.iter().filter(|result| result.is_none()).count();
+    let failed = results.iter().flatten().filter(|(_, ok)| !ok).count();
+    let outputs: Vec<_> = results
+        .into_iter()
+        .flatten()
+        .map(|(output, _)| output)
         .collect();
 
-    for result in results {
-        println!("{}", result);
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&outputs).context("Failed to serialize JSON")?
+        );
+    } else {
+        for output in outputs {
+            println!("{}", output);
+        }
     }
 
-    Ok(())
+    match (failed, skipped) {
+        (0, _) => Ok(()),
+        (_, 0) => anyhow::bail!("{failed} of {total} items failed"),
+        _ => anyhow::bail!("{failed} of {total} items failed ({skipped} skipped by --fail-fast)"),
+    }
 }
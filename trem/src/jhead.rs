@@ -19,20 +19,25 @@ pub struct Args {
     num_items: usize,
 }
 
-pub fn run(args: &Args) -> Result<()> {
-    let reader: Box<dyn Read> = if args.filename == "-" {
+/// Open the file for reading, or stdin for `-`, decompressing `.gz` and `.zst` files.
+pub fn open_input(filename: &str) -> Result<Box<dyn Read>> {
+    Ok(if filename == "-" {
         Box::new(io::stdin())
     } else {
-        let file = File::open(&args.filename).context("Failed to open file")?;
-        if args.filename.ends_with(".gz") || args.filename.ends_with(".json.gz") {
+        let file =
+            File::open(filename).with_context(|| format!("Failed to open file: {filename}"))?;
+        if filename.ends_with(".gz") || filename.ends_with(".json.gz") {
             Box::new(GzDecoder::new(file))
-        } else if args.filename.ends_with(".zst") || args.filename.ends_with(".json.zst") {
+        } else if filename.ends_with(".zst") || filename.ends_with(".json.zst") {
             Box::new(ZstdDecoder::new(file).context("Failed to create zstd decoder")?)
         } else {
             Box::new(file)
         }
-    };
-    let mut reader = BufReader::new(reader);
+    })
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let mut reader = BufReader::new(open_input(&args.filename)?);
 
     // Check for opening bracket
     let mut byte = [0u8; 1];
@@ -27,7 +27,7 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Show pretty-printed git blame for file
+    /// Show pretty-printed git blame for files
     Blame(blame::Args),
 
     /// Run a command on each string in a JSON array
@@ -1,9 +1,16 @@
 //! Show weekly calendar until date and (optionally) print TODO list.
 
 use anyhow::{Context, Result};
-use chrono::{Datelike, NaiveDate, Utc};
-use clap::Parser;
-use std::{fs, path::PathBuf};
+use chrono::{Datelike, Locale, Months, NaiveDate, Utc, Weekday};
+use clap::{Parser, ValueEnum};
+use serde_json::json;
+use std::{
+    env, fs,
+    io::{self, IsTerminal},
+    path::PathBuf,
+};
+use terminal_size::{Height, Width};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 const UNDERLINE: &str = "\x1B[4m";
 const BOLD: &str = "\x1B[1m";
@@ -25,15 +32,33 @@ const HELP_TEMPLATE: &str = "\
 #[command(arg_required_else_help = true)]
 #[command(help_template = HELP_TEMPLATE)]
 struct Args {
-    /// Start date in YYYY-MM-DD format
+    /// Start date in YYYY-MM-DD format, or relative like `today` or `+2w`
+    #[arg(allow_hyphen_values = true)]
     start_date: String,
 
-    /// End date in YYYY-MM-DD format
-    end_date: String,
+    /// End date in YYYY-MM-DD format, or relative like `today+90d` or `friday`
+    #[arg(
+        allow_hyphen_values = true,
+        required_unless_present_any = ["weeks", "days"],
+        conflicts_with_all = ["weeks", "days"]
+    )]
+    end_date: Option<String>,
 
-    /// Path to the todo list file
+    /// Show N weeks from the start date instead of giving an end date
+    #[arg(long, value_name = "N", conflicts_with = "days")]
+    weeks: Option<u32>,
+
+    /// Show N days from the start date instead of giving an end date
+    #[arg(long, value_name = "N")]
+    days: Option<u32>,
+
+    /// Treat this date as today instead of the current date (relative dates are based on it)
+    #[arg(long, allow_hyphen_values = true)]
+    today: Option<String>,
+
+    /// Path to a todo list file (can be repeated)
     #[arg(long)]
-    todo: Option<PathBuf>,
+    todo: Vec<PathBuf>,
 
     /// Start date of vacation in YYYY-MM-DD format
     #[arg(long)]
@@ -43,9 +68,198 @@ struct Args {
     #[arg(long)]
     vacation_end: Option<String>,
 
-    /// Maximum number of lines to print from TODO
+    /// Vacation period in YYYY-MM-DD:YYYY-MM-DD format (can be repeated)
+    #[arg(long)]
+    vacation: Vec<String>,
+
+    /// Path to a file of holidays, one YYYY-MM-DD date per line
+    #[arg(long)]
+    holidays: Option<PathBuf>,
+
+    /// Print a divider before weeks where a new month starts
+    #[arg(long)]
+    mark_months: bool,
+
+    /// Print a divider before weeks where a new quarter starts
+    #[arg(long)]
+    mark_quarters: bool,
+
+    /// Lay the weeks out in N columns (by default, as many as fit the terminal when the
+    /// weeks don't fit its height)
+    #[arg(long, value_name = "N")]
+    columns: Option<usize>,
+
+    /// Width to fit the columns to, instead of the terminal's (e.g. when piping)
+    #[arg(long)]
+    width: Option<usize>,
+
+    /// Count only Monday to Friday in the statistics
+    #[arg(long)]
+    weekdays_only: bool,
+
+    /// Path to a file of milestones, one `YYYY-MM-DD Label` per line
+    #[arg(long)]
+    milestones: Option<PathBuf>,
+
+    /// Unit listed first in the statistics (auto picks weeks for ranges of 8 weeks or more)
+    #[arg(long, value_enum, default_value_t = Units::Days)]
+    units: Units,
+
+    /// Maximum number of lines to print from each TODO file
     #[arg(long, default_value = "10")]
     max_lines: usize,
+
+    /// Print the calendar and statistics as JSON instead
+    #[arg(long)]
+    json: bool,
+
+    /// Print the calendar, statistics and TODO list as Markdown instead
+    #[arg(long, conflicts_with = "json")]
+    markdown: bool,
+
+    /// Print the state of each day in the range as CSV instead
+    #[arg(long, conflicts_with_all = ["json", "markdown"])]
+    csv: bool,
+
+    /// Also write the date range and vacations to an iCalendar (.ics) file
+    #[arg(long)]
+    ics: Option<PathBuf>,
+
+    /// When to use colors and text styles
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Set of symbols used for the days
+    #[arg(long, value_enum, default_value_t = Theme::Unicode)]
+    theme: Theme,
+
+    /// Symbol for today (overrides the theme)
+    #[arg(long)]
+    symbol_today: Option<String>,
+
+    /// Symbol for past days (overrides the theme)
+    #[arg(long)]
+    symbol_past: Option<String>,
+
+    /// Symbol for future days (overrides the theme)
+    #[arg(long)]
+    symbol_future: Option<String>,
+
+    /// Symbol for vacation days (overrides the theme)
+    #[arg(long)]
+    symbol_vacation: Option<String>,
+
+    /// Symbol for holidays (overrides the theme)
+    #[arg(long)]
+    symbol_holiday: Option<String>,
+
+    /// Symbol for days outside the date range (overrides the theme)
+    #[arg(long)]
+    symbol_outside: Option<String>,
+
+    /// Symbol for days with a dated todo (overrides the theme)
+    #[arg(long)]
+    symbol_deadline: Option<String>,
+
+    /// Symbol for milestones (overrides the theme)
+    #[arg(long)]
+    symbol_milestone: Option<String>,
+
+    /// Locale for month and weekday names, e.g. `de_DE` (English by default)
+    #[arg(long, value_parser = parse_locale)]
+    locale: Option<Locale>,
+
+    /// First day of the week
+    #[arg(long, value_enum, default_value_t = WeekStart::Mon)]
+    week_start: WeekStart,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// `auto` styles the output only if it's a terminal and `NO_COLOR` is not set.
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Theme {
+    Unicode,
+    /// For terminals that can't render the Unicode blocks
+    Ascii,
+}
+
+#[derive(Debug)]
+struct Symbols {
+    vacation: String,
+    holiday: String,
+    outside: String,
+    today: String,
+    past: String,
+    future: String,
+    deadline: String,
+    milestone: String,
+}
+
+impl Symbols {
+    fn from_args(args: &Args) -> Self {
+        let [vacation, holiday, outside, today, past, future, deadline, milestone] =
+            match args.theme {
+                Theme::Unicode => ["V", "H", "·", "◈", "◼", "◻", "★", "◆"],
+                Theme::Ascii => ["V", "H", "-", "*", "#", ".", "!", "^"],
+            };
+        let pick = |custom: &Option<String>, default: &str| {
+            custom.clone().unwrap_or_else(|| default.to_string())
+        };
+        Symbols {
+            vacation: pick(&args.symbol_vacation, vacation),
+            holiday: pick(&args.symbol_holiday, holiday),
+            outside: pick(&args.symbol_outside, outside),
+            today: pick(&args.symbol_today, today),
+            past: pick(&args.symbol_past, past),
+            future: pick(&args.symbol_future, future),
+            deadline: pick(&args.symbol_deadline, deadline),
+            milestone: pick(&args.symbol_milestone, milestone),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Units {
+    Days,
+    Weeks,
+    Auto,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum WeekStart {
+    Mon,
+    Sun,
+}
+
+impl WeekStart {
+    fn weekday(self) -> Weekday {
+        match self {
+            WeekStart::Mon => Weekday::Mon,
+            WeekStart::Sun => Weekday::Sun,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Milestone {
+    date: NaiveDate,
+    label: String,
 }
 
 #[derive(Debug)]
@@ -53,21 +267,30 @@ struct CalendarDates {
     start: NaiveDate,
     end: NaiveDate,
     today: NaiveDate,
-    vacation_start: Option<NaiveDate>,
-    vacation_end: Option<NaiveDate>,
+    vacations: Vec<(NaiveDate, NaiveDate)>,
+    holidays: Vec<NaiveDate>,
+    deadlines: Vec<NaiveDate>,
+    milestones: Vec<Milestone>,
+    week_start: WeekStart,
+    weekdays_only: bool,
 }
 
-fn validate_dates(
-    start: NaiveDate,
-    end: NaiveDate,
-    vacation_start: Option<NaiveDate>,
-    vacation_end: Option<NaiveDate>,
-) -> Result<CalendarDates> {
+/// Check the dates are consistent, and sort them and drop the ones outside the range.
+fn validate_dates(dates: CalendarDates) -> Result<CalendarDates> {
+    let CalendarDates {
+        start,
+        end,
+        mut vacations,
+        holidays,
+        mut deadlines,
+        milestones,
+        ..
+    } = dates;
     if start > end {
         anyhow::bail!("End date must be after start date");
     }
 
-    if let (Some(vstart), Some(vend)) = (vacation_start, vacation_end) {
+    for &(vstart, vend) in &vacations {
         if vstart > vend {
             anyhow::bail!("Vacation start date must be before or equal to vacation end date");
         }
@@ -76,65 +299,313 @@ fn validate_dates(
         }
     }
 
+    vacations.sort();
+    for pair in vacations.windows(2) {
+        let ((prev_start, prev_end), (next_start, next_end)) = (pair[0], pair[1]);
+        if next_start <= prev_end {
+            anyhow::bail!(
+                "Vacation periods overlap: {} to {} and {} to {}",
+                prev_start.format("%Y-%m-%d"),
+                prev_end.format("%Y-%m-%d"),
+                next_start.format("%Y-%m-%d"),
+                next_end.format("%Y-%m-%d")
+            );
+        }
+    }
+
+    let (mut holidays, outside): (Vec<_>, Vec<_>) = holidays
+        .into_iter()
+        .partition(|&day| day >= start && day <= end);
+    for day in outside {
+        eprintln!(
+            "Warning: ignoring holiday outside the date range: {}",
+            day.format("%Y-%m-%d")
+        );
+    }
+    holidays.sort();
+    holidays.dedup();
+
+    deadlines.retain(|&day| day >= start && day <= end);
+    deadlines.sort();
+    deadlines.dedup();
+
+    let (mut milestones, outside): (Vec<_>, Vec<_>) = milestones
+        .into_iter()
+        .partition(|milestone| milestone.date >= start && milestone.date <= end);
+    for milestone in outside {
+        eprintln!(
+            "Note: skipping milestone outside the date range: {} {}",
+            milestone.date.format("%Y-%m-%d"),
+            milestone.label
+        );
+    }
+    milestones.sort_by_key(|milestone| milestone.date);
+
     Ok(CalendarDates {
-        start,
-        end,
-        today: Utc::now().date_naive(),
-        vacation_start,
-        vacation_end,
+        vacations,
+        holidays,
+        deadlines,
+        milestones,
+        ..dates
     })
 }
 
-fn is_vacation_day(day: NaiveDate, vacation: (Option<NaiveDate>, Option<NaiveDate>)) -> bool {
-    match vacation {
-        (Some(start), Some(end)) => day >= start && day <= end,
-        _ => false,
-    }
+fn is_vacation_day(day: NaiveDate, vacations: &[(NaiveDate, NaiveDate)]) -> bool {
+    vacations
+        .iter()
+        .any(|&(start, end)| day >= start && day <= end)
 }
 
-fn generate_week_calendar(week_start: NaiveDate, dates: &CalendarDates) -> String {
-    let days = (0..7).map(|i| {
-        let day = week_start + chrono::Duration::days(i);
-        if is_vacation_day(day, (dates.vacation_start, dates.vacation_end)) {
-            "V"
+#[derive(Debug, Clone, Copy)]
+enum DayState {
+    Vacation,
+    Holiday,
+    Outside,
+    Today,
+    Past,
+    Future,
+}
+
+impl DayState {
+    fn of(day: NaiveDate, dates: &CalendarDates) -> Self {
+        if is_vacation_day(day, &dates.vacations) {
+            DayState::Vacation
+        } else if dates.holidays.contains(&day) {
+            DayState::Holiday
         } else if day < dates.start || day > dates.end {
-            "·"
+            DayState::Outside
         } else if day == dates.today {
-            "◈"
+            DayState::Today
         } else if day < dates.today {
-            "◼"
+            DayState::Past
         } else {
-            "◻"
+            DayState::Future
         }
-    });
+    }
 
+    fn symbol(self, symbols: &Symbols) -> &str {
+        match self {
+            DayState::Vacation => &symbols.vacation,
+            DayState::Holiday => &symbols.holiday,
+            DayState::Outside => &symbols.outside,
+            DayState::Today => &symbols.today,
+            DayState::Past => &symbols.past,
+            DayState::Future => &symbols.future,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DayState::Vacation => "vacation",
+            DayState::Holiday => "holiday",
+            DayState::Outside => "outside",
+            DayState::Today => "today",
+            DayState::Past => "past",
+            DayState::Future => "future",
+        }
+    }
+}
+
+/// The symbol for a day, with milestones and dated todos marked over the day's state.
+fn day_symbol<'a>(day: NaiveDate, dates: &CalendarDates, symbols: &'a Symbols) -> &'a str {
+    if dates
+        .milestones
+        .iter()
+        .any(|milestone| milestone.date == day)
+    {
+        &symbols.milestone
+    } else if dates.deadlines.contains(&day) {
+        &symbols.deadline
+    } else {
+        DayState::of(day, dates).symbol(symbols)
+    }
+}
+
+fn week_days(week_start: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+    (0..7).map(move |i| week_start + chrono::Duration::days(i))
+}
+
+fn is_current_week(week_start: NaiveDate, dates: &CalendarDates) -> bool {
+    !is_vacation_day(week_start, &dates.vacations)
+        && week_start <= dates.today
+        && week_start + chrono::Duration::days(7) > dates.today
+}
+
+fn paint(text: &str, style: &str, color: bool) -> String {
+    if color {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Width of the longest week label in the locale, so that the days stay aligned when month
+/// abbreviations differ in length.
+fn week_label_width(locale: Locale) -> usize {
+    (1..=12)
+        .filter_map(|month| NaiveDate::from_ymd_opt(2000, month, 1))
+        .map(|day| day.format_localized("%b %d", locale).to_string().width())
+        .max()
+        .unwrap_or(0)
+}
+
+fn generate_week_calendar(
+    week_start: NaiveDate,
+    dates: &CalendarDates,
+    symbols: &Symbols,
+    locale: Locale,
+    color: bool,
+) -> String {
+    let days = week_days(week_start).map(|day| day_symbol(day, dates, symbols));
+
+    // `{:<width$}` pads by chars, which misaligns labels with wide characters.
+    let label = week_start.format_localized("%b %d", locale).to_string();
+    let padding = week_label_width(locale).saturating_sub(label.width());
     let week_str = format!(
-        "{} {}",
-        week_start.format("%b %d"),
+        "{}{} {}",
+        label,
+        " ".repeat(padding),
         days.collect::<Vec<_>>().join(" ")
     );
 
-    let should_underline = !is_vacation_day(week_start, (dates.vacation_start, dates.vacation_end))
-        && week_start <= dates.today
-        && week_start + chrono::Duration::days(7) > dates.today;
-
-    if should_underline {
-        format!("{}{}{}", UNDERLINE, week_str, RESET)
+    if is_current_week(week_start, dates) {
+        paint(&week_str, UNDERLINE, color)
     } else {
         week_str
     }
 }
 
-fn generate_calendar(dates: &'_ CalendarDates) -> impl Iterator<Item = String> + '_ {
-    (dates.start.num_days_from_ce()..=dates.end.num_days_from_ce())
+fn week_starts(dates: &CalendarDates) -> impl Iterator<Item = NaiveDate> {
+    // Adjust to start of week, then step a week at a time so the week containing
+    // the end date is always included.
+    let offset = dates.start.weekday().days_since(dates.week_start.weekday());
+    let first_week = dates.start.num_days_from_ce() - offset as i32;
+    (first_week..=dates.end.num_days_from_ce())
         .step_by(7)
         .filter_map(NaiveDate::from_num_days_from_ce_opt)
-        .map(move |current| {
-            // Adjust to start of week (Monday)
-            let week_start =
-                current - chrono::Duration::days(current.weekday().num_days_from_monday() as i64);
-            generate_week_calendar(week_start, dates)
+}
+
+/// Which boundaries get a divider line in the calendar.
+#[derive(Debug, Clone, Copy)]
+struct Boundaries {
+    months: bool,
+    quarters: bool,
+}
+
+/// The label for the month or quarter starting during the week within the date range, if it
+/// should be marked.
+fn boundary_label(
+    week_start: NaiveDate,
+    dates: &CalendarDates,
+    boundaries: Boundaries,
+    locale: Locale,
+) -> Option<String> {
+    let first = week_days(week_start)
+        .find(|&day| day.day() == 1 && day >= dates.start && day <= dates.end)?;
+    let quarter = (first.month0() % 3 == 0).then(|| first.month0() / 3 + 1);
+    match (
+        boundaries.months,
+        boundaries.quarters.then_some(quarter).flatten(),
+    ) {
+        (true, Some(quarter)) => Some(format!(
+            "Q{} - {}",
+            quarter,
+            first.format_localized("%B %Y", locale)
+        )),
+        (true, None) => Some(first.format_localized("%B %Y", locale).to_string()),
+        (false, Some(quarter)) => Some(format!("Q{} {}", quarter, first.year())),
+        (false, None) => None,
+    }
+}
+
+fn generate_calendar<'a>(
+    dates: &'a CalendarDates,
+    symbols: &'a Symbols,
+    boundaries: Boundaries,
+    locale: Locale,
+    color: bool,
+) -> impl Iterator<Item = String> + 'a {
+    week_starts(dates).flat_map(move |week_start| {
+        // Dividers go above their week, so the first week can have one too.
+        let divider = boundary_label(week_start, dates, boundaries, locale)
+            .map(|label| format!("-- {} --", label));
+        divider
+            .into_iter()
+            .chain(std::iter::once(generate_week_calendar(
+                week_start, dates, symbols, locale, color,
+            )))
+    })
+}
+
+const COLUMN_GAP: &str = "   ";
+
+/// Width of the text in terminal columns, skipping ANSI escape sequences.
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1B' {
+            chars.by_ref().find(|&c| c == 'm');
+        } else {
+            width += c.width().unwrap_or(0);
+        }
+    }
+    width
+}
+
+/// How many columns to lay the calendar lines out in: `--columns` if given, otherwise as
+/// many as fit `--width`, or the terminal if the lines and the `reserved` lines printed
+/// around them are taller than it.
+fn calendar_columns(args: &Args, lines: &[String], reserved: usize) -> usize {
+    if let Some(columns) = args.columns {
+        return columns.max(1);
+    }
+    let (width, height) = match (args.width, terminal_size::terminal_size()) {
+        (Some(width), _) => (width, None),
+        (None, Some((Width(width), Height(height)))) => (width.into(), Some(height.into())),
+        (None, None) => return 1,
+    };
+    if height.is_some_and(|height: usize| lines.len() + reserved <= height) {
+        return 1;
+    }
+    let line_width = lines
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+    ((width + COLUMN_GAP.len()) / (line_width + COLUMN_GAP.len())).max(1)
+}
+
+/// Lay the lines out top to bottom, then left to right, in the given number of columns.
+fn layout_columns(lines: Vec<String>, columns: usize) -> Vec<String> {
+    if columns <= 1 {
+        return lines;
+    }
+    let rows = lines.len().div_ceil(columns);
+    let line_width = lines
+        .iter()
+        .map(|line| visible_width(line))
+        .max()
+        .unwrap_or(0);
+    (0..rows)
+        .map(|row| {
+            let cells: Vec<_> = lines.iter().skip(row).step_by(rows).collect();
+            let last = cells.len() - 1;
+            cells
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| {
+                    let padding = if i == last {
+                        0
+                    } else {
+                        line_width - visible_width(cell)
+                    };
+                    format!("{}{}", cell, " ".repeat(padding))
+                })
+                .collect::<Vec<_>>()
+                .join(COLUMN_GAP)
         })
+        .collect()
 }
 
 fn count_days(start: NaiveDate, end: NaiveDate, predicate: impl Fn(NaiveDate) -> bool) -> usize {
@@ -146,11 +617,32 @@ fn count_days(start: NaiveDate, end: NaiveDate, predicate: impl Fn(NaiveDate) ->
         .count()
 }
 
-fn get_statistics(dates: &CalendarDates) -> String {
-    let is_not_vacation = |date| !is_vacation_day(date, (dates.vacation_start, dates.vacation_end));
-    let is_passed = |date| date <= dates.today && is_not_vacation(date);
+struct Statistics {
+    days_passed: usize,
+    days_remaining: usize,
+    total_days: usize,
+    percentage: f64,
+    /// Days counted per week: 5 with `--weekdays-only`, otherwise 7.
+    week_length: usize,
+}
+
+impl Statistics {
+    fn weeks(&self, days: usize) -> f64 {
+        days as f64 / self.week_length as f64
+    }
+}
 
-    let total_days = count_days(dates.start, dates.end, is_not_vacation);
+fn compute_statistics(dates: &CalendarDates) -> Statistics {
+    let is_weekend = |date: NaiveDate| matches!(date.weekday(), Weekday::Sat | Weekday::Sun);
+    let is_off = |date| {
+        is_vacation_day(date, &dates.vacations)
+            || dates.holidays.contains(&date)
+            || (dates.weekdays_only && is_weekend(date))
+    };
+    let is_not_off = |date| !is_off(date);
+    let is_passed = |date| date <= dates.today && is_not_off(date);
+
+    let total_days = count_days(dates.start, dates.end, is_not_off);
     let days_passed = count_days(dates.start, dates.end, is_passed);
     let days_remaining = total_days - days_passed;
 
@@ -160,7 +652,26 @@ fn get_statistics(dates: &CalendarDates) -> String {
         0.0
     };
 
-    format!(
+    Statistics {
+        days_passed,
+        days_remaining,
+        total_days,
+        percentage,
+        week_length: if dates.weekdays_only { 5 } else { 7 },
+    }
+}
+
+fn get_statistics(dates: &CalendarDates, units: Units) -> String {
+    let stats = compute_statistics(dates);
+    let Statistics {
+        days_passed,
+        days_remaining,
+        total_days,
+        percentage,
+        ..
+    } = stats;
+
+    let days = format!(
         "Days passed:    {:3} ({:.2}%)\n\
          Days remaining: {:3} ({:.2}%)\n\
          Total days:     {:3}",
@@ -169,71 +680,742 @@ fn get_statistics(dates: &CalendarDates) -> String {
         days_remaining,
         (1.0 - percentage) * 100.0,
         total_days
-    )
+    );
+    let weeks = format!(
+        "Weeks passed:    {:4.1}\n\
+         Weeks remaining: {:4.1}\n\
+         Total weeks:     {:4.1}",
+        stats.weeks(days_passed),
+        stats.weeks(days_remaining),
+        stats.weeks(total_days)
+    );
+
+    let weeks_first = match units {
+        Units::Days => false,
+        Units::Weeks => true,
+        Units::Auto => stats.weeks(total_days) >= 8.0,
+    };
+    if weeks_first {
+        format!("{}\n{}", weeks, days)
+    } else {
+        format!("{}\n{}", days, weeks)
+    }
 }
 
-fn parse_date(date_str: &str) -> Result<NaiveDate> {
+fn generate_json(dates: &CalendarDates) -> serde_json::Value {
+    let format_date = |date: NaiveDate| date.format("%Y-%m-%d").to_string();
+    let weeks: Vec<_> = week_starts(dates)
+        .map(|week_start| {
+            let days: Vec<_> = week_days(week_start)
+                .map(|day| {
+                    json!({
+                        "date": format_date(day),
+                        "state": DayState::of(day, dates).name(),
+                    })
+                })
+                .collect();
+            json!({ "start": format_date(week_start), "days": days })
+        })
+        .collect();
+    let vacations: Vec<_> = dates
+        .vacations
+        .iter()
+        .map(|&(vstart, vend)| json!({ "start": format_date(vstart), "end": format_date(vend) }))
+        .collect();
+    let holidays: Vec<_> = dates.holidays.iter().map(|&day| format_date(day)).collect();
+    let stats = compute_statistics(dates);
+
+    json!({
+        "start": format_date(dates.start),
+        "end": format_date(dates.end),
+        "today": format_date(dates.today),
+        "vacations": vacations,
+        "holidays": holidays,
+        "weeks": weeks,
+        "statistics": {
+            "days_passed": stats.days_passed,
+            "days_remaining": stats.days_remaining,
+            "total_days": stats.total_days,
+            "percentage_passed": stats.percentage * 100.0,
+            "weeks_passed": stats.weeks(stats.days_passed),
+            "weeks_remaining": stats.weeks(stats.days_remaining),
+            "total_weeks": stats.weeks(stats.total_days),
+        },
+    })
+}
+
+fn generate_ics(dates: &CalendarDates) -> String {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    // All-day events end on the day after their last day.
+    let event = |summary: &str, start: NaiveDate, end: NaiveDate| {
+        let end = end + chrono::Duration::days(1);
+        [
+            "BEGIN:VEVENT".to_string(),
+            format!(
+                "UID:{}-{}-{}@weekly-calendar",
+                summary.to_lowercase(),
+                start.format("%Y%m%d"),
+                end.format("%Y%m%d")
+            ),
+            format!("DTSTAMP:{}", stamp),
+            format!("DTSTART;VALUE=DATE:{}", start.format("%Y%m%d")),
+            format!("DTEND;VALUE=DATE:{}", end.format("%Y%m%d")),
+            format!("SUMMARY:{}", summary),
+            "END:VEVENT".to_string(),
+        ]
+    };
+
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//oyarsa//weekly-calendar//EN".to_string(),
+    ];
+    lines.extend(event("Calendar", dates.start, dates.end));
+    for &(vstart, vend) in &dates.vacations {
+        lines.extend(event("Vacation", vstart, vend));
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    // iCalendar requires CRLF line endings.
+    lines.iter().map(|line| format!("{}\r\n", line)).collect()
+}
+
+fn generate_csv(dates: &CalendarDates) -> String {
+    let mut lines = vec!["date,weekday,state".to_string()];
+    for days in dates.start.num_days_from_ce()..=dates.end.num_days_from_ce() {
+        let day = NaiveDate::from_num_days_from_ce_opt(days).expect("Date range is always valid.");
+        lines.push(format!(
+            "{},{},{}",
+            day.format("%Y-%m-%d"),
+            day.format("%a"),
+            DayState::of(day, dates).name()
+        ));
+    }
+    lines.join("\n")
+}
+
+fn generate_markdown(
+    dates: &CalendarDates,
+    symbols: &Symbols,
+    todos: &[TodoList],
+    locale: Locale,
+) -> String {
+    let format_date = |date: NaiveDate| date.format("%Y-%m-%d").to_string();
+    let mut lines = vec![
+        "# Weekly Calendar".to_string(),
+        String::new(),
+        format!("- From: {}", format_date(dates.start)),
+        format!("- To: {}", format_date(dates.end)),
+        format!("- Today: {}", format_date(dates.today)),
+        String::new(),
+    ];
+
+    if !dates.vacations.is_empty() {
+        lines.push("## Vacations".to_string());
+        lines.push(String::new());
+        for &(vstart, vend) in &dates.vacations {
+            lines.push(format!(
+                "- {} to {}",
+                format_date(vstart),
+                format_date(vend)
+            ));
+        }
+        lines.push(String::new());
+    }
+
+    let weekdays: Vec<_> = week_days(week_starts(dates).next().unwrap_or(dates.start))
+        .map(|day| day.format_localized("%a", locale).to_string())
+        .collect();
+    lines.push(format!("| Week | {} |", weekdays.join(" | ")));
+    lines.push(format!("|---|{}", "---|".repeat(7)));
+    for week_start in week_starts(dates) {
+        let days: Vec<_> = week_days(week_start)
+            .map(|day| day_symbol(day, dates, symbols))
+            .collect();
+        // Bold takes the place of the terminal underline for the current week.
+        let label = if is_current_week(week_start, dates) {
+            format!("**{}**", week_start.format_localized("%b %d", locale))
+        } else {
+            week_start.format_localized("%b %d", locale).to_string()
+        };
+        lines.push(format!("| {} | {} |", label, days.join(" | ")));
+    }
+
+    let stats = compute_statistics(dates);
+    lines.extend([
+        String::new(),
+        format!(
+            "- Days passed: {} ({:.2}%)",
+            stats.days_passed,
+            stats.percentage * 100.0
+        ),
+        format!(
+            "- Days remaining: {} ({:.2}%)",
+            stats.days_remaining,
+            (1.0 - stats.percentage) * 100.0
+        ),
+        format!("- Total days: {}", stats.total_days),
+        format!("- Weeks passed: {:.1}", stats.weeks(stats.days_passed)),
+        format!(
+            "- Weeks remaining: {:.1}",
+            stats.weeks(stats.days_remaining)
+        ),
+        format!("- Total weeks: {:.1}", stats.weeks(stats.total_days)),
+    ]);
+
+    let upcoming = get_upcoming_milestones(dates);
+    if !upcoming.is_empty() {
+        lines.push(String::new());
+        lines.push("## Upcoming Milestones".to_string());
+        lines.push(String::new());
+        lines.extend(upcoming);
+    }
+
+    if !todos.is_empty() {
+        lines.push(String::new());
+        lines.push("## Todo List".to_string());
+    }
+    for todo_list in todos {
+        lines.push(String::new());
+        if todos.len() > 1 {
+            lines.push(format!("### {}", todo_list.title));
+            lines.push(String::new());
+        }
+        for todo in todo_list
+            .items
+            .iter()
+            .filter(|todo| !todo.trim().is_empty())
+        {
+            let item = todo.trim_start();
+            if item.starts_with("- ") || item.starts_with("* ") {
+                lines.push(todo.clone());
+            } else {
+                lines.push(format!("- {}", todo));
+            }
+        }
+    }
+
+    lines.join("\n")
+}
+
+/// Parse a date in YYYY-MM-DD format, or relative to today:
+///
+/// - `today`, `tomorrow` or `yesterday`
+/// - `today+N`, `today-N`, `+N` or `-N`, where `N` is a number of days, or of weeks or
+///   months with a `w` or `m` suffix (e.g. `+2w`, `today+90d`)
+/// - a weekday name (`monday` or `mon`): the next such day, or today if it matches
+fn parse_date(date_str: &str, today: NaiveDate) -> Result<NaiveDate> {
     NaiveDate::parse_from_str(date_str, "%Y-%m-%d")
-        .with_context(|| format!("Failed to parse date: {}", date_str))
-}
-
-fn read_todo_list(path: &PathBuf, max_lines: usize) -> Result<Vec<String>> {
-    fs::read_to_string(path)
-        .with_context(|| format!("Failed to read todo file: {}", path.display()))
-        .map(|content| {
-            content
-                .lines()
-                .take(max_lines)
-                .map(|line| line.trim_end().to_string())
-                .collect()
+        .ok()
+        .or_else(|| parse_relative_date(date_str, today))
+        .with_context(|| {
+            format!(
+                "Failed to parse date: {} (expected YYYY-MM-DD, today, today+N[d|w|m], \
+                 +N[d|w|m] or a weekday)",
+                date_str
+            )
+        })
+}
+
+fn parse_relative_date(input: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let input = input.trim().to_lowercase();
+    match input.as_str() {
+        "today" => return Some(today),
+        "tomorrow" => return today.succ_opt(),
+        "yesterday" => return today.pred_opt(),
+        _ => {}
+    }
+    if let Ok(weekday) = input.parse::<Weekday>() {
+        let days_ahead = weekday.days_since(today.weekday());
+        return Some(today + chrono::Duration::days(days_ahead as i64));
+    }
+
+    let offset = input.strip_prefix("today").unwrap_or(&input);
+    let (negative, amount) = match offset.split_at_checked(1)? {
+        ("+", amount) => (false, amount),
+        ("-", amount) => (true, amount),
+        _ => return None,
+    };
+    let unit_pos = amount
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(amount.len());
+    let (number, unit) = amount.split_at(unit_pos);
+    let n: u32 = number.parse().ok()?;
+    let days = |n: i64| {
+        let delta = chrono::Duration::days(n);
+        if negative {
+            today.checked_sub_signed(delta)
+        } else {
+            today.checked_add_signed(delta)
+        }
+    };
+    match unit {
+        "" | "d" => days(n.into()),
+        "w" => days(i64::from(n) * 7),
+        "m" if negative => today.checked_sub_months(Months::new(n)),
+        "m" => today.checked_add_months(Months::new(n)),
+        _ => None,
+    }
+}
+
+fn read_milestones(path: &PathBuf, today: NaiveDate) -> Result<Vec<Milestone>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read milestones file: {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let (date, label) = line
+                .split_once(char::is_whitespace)
+                .context("Expected a date and a label: YYYY-MM-DD Label")
+                .and_then(|(date, label)| Ok((parse_date(date, today)?, label.trim())))
+                .with_context(|| format!("{}:{}", path.display(), i + 1))?;
+            Ok(Milestone {
+                date,
+                label: label.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The upcoming milestones, with how long until each one.
+fn get_upcoming_milestones(dates: &CalendarDates) -> Vec<String> {
+    dates
+        .milestones
+        .iter()
+        .filter(|milestone| milestone.date >= dates.today)
+        .map(|milestone| {
+            let countdown = match (milestone.date - dates.today).num_days() {
+                0 => "today".to_string(),
+                1 => "in 1 day".to_string(),
+                days => format!("in {} days", days),
+            };
+            format!(
+                "- {} {} ({})",
+                milestone.date.format("%Y-%m-%d"),
+                milestone.label,
+                countdown
+            )
+        })
+        .collect()
+}
+
+/// Parse a locale such as `de_DE`, ignoring an encoding or modifier as in `LC_TIME` values
+/// like `de_DE.UTF-8`.
+fn parse_locale(locale: &str) -> Result<Locale, String> {
+    let name = locale.split(['.', '@']).next().unwrap_or_default();
+    name.parse()
+        .map_err(|_| format!("unknown locale: {} (expected e.g. de_DE)", locale))
+}
+
+fn parse_vacation(period: &str, today: NaiveDate) -> Result<(NaiveDate, NaiveDate)> {
+    let (start, end) = period
+        .split_once(':')
+        .with_context(|| format!("Vacation must be in START:END format: {}", period))?;
+    Ok((parse_date(start, today)?, parse_date(end, today)?))
+}
+
+/// Read holiday dates, one per line. Blank lines and `#` comments are skipped.
+fn read_holidays(path: &PathBuf, today: NaiveDate) -> Result<Vec<NaiveDate>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read holidays file: {}", path.display()))?;
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            parse_date(line, today).with_context(|| format!("{}:{}", path.display(), i + 1))
         })
+        .collect()
+}
+
+/// A todo file's first lines, titled by its file name, and the dates of all its dated
+/// todos.
+struct TodoList {
+    title: String,
+    items: Vec<String>,
+    deadlines: Vec<NaiveDate>,
+}
+
+/// The date of a todo line starting with one, such as `2024-08-12 Ship release` (after an
+/// optional `-` or `*` bullet).
+fn todo_deadline(line: &str) -> Option<NaiveDate> {
+    let item = line.trim_start();
+    let item = item
+        .strip_prefix("- ")
+        .or_else(|| item.strip_prefix("* "))
+        .unwrap_or(item);
+    let date = item.split_whitespace().next()?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+fn read_todo_list(path: &PathBuf, max_lines: usize) -> Result<TodoList> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read todo file: {}", path.display()))?;
+    let title = path.file_name().map_or_else(
+        || path.display().to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    Ok(TodoList {
+        title,
+        items: content
+            .lines()
+            .take(max_lines)
+            .map(|line| line.trim_end().to_string())
+            .collect(),
+        deadlines: content.lines().filter_map(todo_deadline).collect(),
+    })
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let start = parse_date(&args.start_date)?;
-    let end = parse_date(&args.end_date)?;
+    let today = match &args.today {
+        Some(today) => parse_date(today, Utc::now().date_naive())?,
+        None => Utc::now().date_naive(),
+    };
+    let start = parse_date(&args.start_date, today)?;
+    let end = match (&args.end_date, args.weeks, args.days) {
+        (Some(end_date), _, _) => parse_date(end_date, today)?,
+        (None, Some(weeks), _) => start
+            .checked_add_signed(chrono::Duration::weeks(weeks.into()))
+            .context("Duration is too long")?,
+        (None, None, Some(days)) => start
+            .checked_add_signed(chrono::Duration::days(days.into()))
+            .context("Duration is too long")?,
+        (None, None, None) => unreachable!("clap requires an end date or a duration"),
+    };
+
+    let vacation_start = args
+        .vacation_start
+        .as_deref()
+        .map(|date| parse_date(date, today))
+        .transpose()?;
+    let vacation_end = args
+        .vacation_end
+        .as_deref()
+        .map(|date| parse_date(date, today))
+        .transpose()?;
+
+    let mut vacations = args
+        .vacation
+        .iter()
+        .map(|period| parse_vacation(period, today))
+        .collect::<Result<Vec<_>>>()?;
+    match (vacation_start, vacation_end) {
+        (Some(vstart), Some(vend)) => vacations.push((vstart, vend)),
+        (None, None) => {}
+        _ => anyhow::bail!("Both --vacation-start and --vacation-end must be provided together"),
+    }
+
+    let holidays = match &args.holidays {
+        Some(path) => read_holidays(path, today)?,
+        None => Vec::new(),
+    };
 
-    if end < Utc::now().date_naive() {
+    let todos = args
+        .todo
+        .iter()
+        .map(|path| read_todo_list(path, args.max_lines))
+        .collect::<Result<Vec<_>>>()?;
+    let dates = validate_dates(CalendarDates {
+        start,
+        end,
+        today,
+        vacations,
+        holidays,
+        deadlines: todos
+            .iter()
+            .flat_map(|todo_list| todo_list.deadlines.iter().copied())
+            .collect(),
+        milestones: match &args.milestones {
+            Some(path) => read_milestones(path, today)?,
+            None => Vec::new(),
+        },
+        week_start: args.week_start,
+        weekdays_only: args.weekdays_only,
+    })?;
+    let symbols = Symbols::from_args(&args);
+    let locale = args.locale.unwrap_or(Locale::POSIX);
+
+    if let Some(ics_path) = &args.ics {
+        fs::write(ics_path, generate_ics(&dates))
+            .with_context(|| format!("Failed to write iCalendar file: {}", ics_path.display()))?;
+    }
+
+    if args.json {
+        println!("{:#}", generate_json(&dates));
         return Ok(());
     }
 
-    let vacation_start = args.vacation_start.as_deref().map(parse_date).transpose()?;
-    let vacation_end = args.vacation_end.as_deref().map(parse_date).transpose()?;
+    if args.csv {
+        println!("{}", generate_csv(&dates));
+        return Ok(());
+    }
 
-    if vacation_start.is_some() != vacation_end.is_some() {
-        anyhow::bail!("Both --vacation-start and --vacation-end must be provided together");
+    if args.markdown {
+        println!("{}", generate_markdown(&dates, &symbols, &todos, locale));
+        return Ok(());
     }
 
-    let dates = validate_dates(start, end, vacation_start, vacation_end)?;
+    // Exports cover any range, but there's nothing to track in a calendar that is over.
+    if dates.end < dates.today {
+        return Ok(());
+    }
 
-    println!("{}{}Weekly Calendar:{}", BOLD, UNDERLINE, RESET);
-    println!("From : {}", dates.start.format("%Y-%m-%d"));
-    println!("To   : {}", dates.end.format("%Y-%m-%d"));
-    println!("Today: {}\n", dates.today.format("%Y-%m-%d"));
+    let color = args.color.enabled();
+    let heading = [BOLD, UNDERLINE].concat();
 
-    if let (Some(vstart), Some(vend)) = (vacation_start, vacation_end) {
-        println!("{}Vacations:{}", UNDERLINE, RESET);
-        println!(
-            "- {} to {}\n",
-            vstart.format("%Y-%m-%d"),
-            vend.format("%Y-%m-%d")
-        );
+    // Collect the lines around the calendar first, so the layout can leave room for them.
+    let mut header = vec![
+        paint("Weekly Calendar:", &heading, color),
+        format!("From : {}", dates.start.format("%Y-%m-%d")),
+        format!("To   : {}", dates.end.format("%Y-%m-%d")),
+        format!("Today: {}", dates.today.format("%Y-%m-%d")),
+        String::new(),
+    ];
+
+    if !dates.vacations.is_empty() {
+        header.push(paint("Vacations:", UNDERLINE, color));
+        for (vstart, vend) in &dates.vacations {
+            header.push(format!(
+                "- {} to {}",
+                vstart.format("%Y-%m-%d"),
+                vend.format("%Y-%m-%d")
+            ));
+        }
+        header.push(String::new());
     }
 
-    for line in generate_calendar(&dates) {
-        println!("{}", line);
+    let mut footer = vec![String::new()];
+    footer.extend(
+        get_statistics(&dates, args.units)
+            .lines()
+            .map(str::to_string),
+    );
+
+    let upcoming = get_upcoming_milestones(&dates);
+    if !upcoming.is_empty() {
+        footer.push(String::new());
+        footer.push(paint("Upcoming Milestones:", &heading, color));
+        footer.extend(upcoming);
     }
-    println!("\n{}", get_statistics(&dates));
 
-    if let Some(todo_path) = args.todo {
-        let todos = read_todo_list(&todo_path, args.max_lines)?;
-        println!("\n{}{}Todo List:{}", BOLD, UNDERLINE, RESET);
-        for todo in todos {
-            println!("{}", todo);
+    if !todos.is_empty() {
+        footer.push(String::new());
+        footer.push(paint("Todo List:", &heading, color));
+    }
+    for (i, todo_list) in todos.iter().enumerate() {
+        if todos.len() > 1 {
+            if i > 0 {
+                footer.push(String::new());
+            }
+            footer.push(paint(&format!("{}:", todo_list.title), UNDERLINE, color));
         }
+        footer.extend(todo_list.items.iter().cloned());
+    }
+
+    let boundaries = Boundaries {
+        months: args.mark_months,
+        quarters: args.mark_quarters,
+    };
+    let lines: Vec<_> = generate_calendar(&dates, &symbols, boundaries, locale, color).collect();
+    let columns = calendar_columns(&args, &lines, header.len() + footer.len());
+    for line in header
+        .into_iter()
+        .chain(layout_columns(lines, columns))
+        .chain(footer)
+    {
+        println!("{}", line);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    // A Friday.
+    fn today() -> NaiveDate {
+        date("2026-10-16")
+    }
+
+    fn relative(input: &str) -> Option<NaiveDate> {
+        parse_relative_date(input, today())
+    }
+
+    #[test]
+    fn relative_today() {
+        assert_eq!(relative("today"), Some(today()));
+        assert_eq!(relative(" Today "), Some(today()));
+    }
+
+    #[test]
+    fn relative_tomorrow() {
+        assert_eq!(relative("tomorrow"), Some(date("2026-10-17")));
+    }
+
+    #[test]
+    fn relative_yesterday() {
+        assert_eq!(relative("yesterday"), Some(date("2026-10-15")));
+    }
+
+    #[test]
+    fn relative_weekday_is_next_occurrence_or_today() {
+        assert_eq!(relative("friday"), Some(today()));
+        assert_eq!(relative("sat"), Some(date("2026-10-17")));
+        assert_eq!(relative("Monday"), Some(date("2026-10-19")));
+        assert_eq!(relative("thu"), Some(date("2026-10-22")));
+    }
+
+    #[test]
+    fn relative_day_and_week_offsets() {
+        assert_eq!(relative("+3d"), Some(date("2026-10-19")));
+        assert_eq!(relative("+3"), Some(date("2026-10-19")));
+        assert_eq!(relative("-1d"), Some(date("2026-10-15")));
+        assert_eq!(relative("+2w"), Some(date("2026-10-30")));
+        assert_eq!(relative("-1w"), Some(date("2026-10-09")));
+        assert_eq!(relative("today+1w"), Some(date("2026-10-23")));
+    }
+
+    #[test]
+    fn relative_rejects_garbage() {
+        assert_eq!(relative("+3y"), None);
+        assert_eq!(relative("3d"), None);
+        assert_eq!(relative("+d"), None);
+        assert_eq!(relative("someday"), None);
+    }
+
+    fn calendar_dates(start: &str, end: &str) -> CalendarDates {
+        CalendarDates {
+            start: date(start),
+            end: date(end),
+            today: today(),
+            vacations: vec![],
+            holidays: vec![],
+            deadlines: vec![],
+            milestones: vec![],
+            week_start: WeekStart::Mon,
+            weekdays_only: false,
+        }
+    }
+
+    fn symbols() -> Symbols {
+        Symbols::from_args(&Args::parse_from([
+            "weekly-calendar",
+            "today",
+            "--days",
+            "1",
+        ]))
+    }
+
+    #[test]
+    fn boundary_in_first_week_gets_divider() {
+        let dates = calendar_dates("2026-10-01", "2026-11-10");
+        let boundaries = Boundaries {
+            months: true,
+            quarters: true,
+        };
+        let lines: Vec<_> =
+            generate_calendar(&dates, &symbols(), boundaries, Locale::en_US, false).collect();
+        assert_eq!(lines[0], "-- Q4 - October 2026 --");
+        assert!(lines[1].starts_with("Sep 28"));
+        assert!(lines.contains(&"-- November 2026 --".to_string()));
+    }
+
+    #[test]
+    fn boundary_after_the_end_gets_no_divider() {
+        // The last week runs from Oct 26 into November.
+        let dates = calendar_dates("2026-10-05", "2026-10-28");
+        let boundaries = Boundaries {
+            months: true,
+            quarters: true,
+        };
+        let lines: Vec<_> =
+            generate_calendar(&dates, &symbols(), boundaries, Locale::en_US, false).collect();
+        assert!(
+            lines.iter().all(|line| !line.starts_with("--")),
+            "{:?}",
+            lines
+        );
+    }
+
+    #[test]
+    fn markdown_lists_upcoming_milestones() {
+        let mut dates = calendar_dates("2026-10-01", "2026-11-10");
+        dates.milestones = vec![
+            Milestone {
+                date: date("2026-10-02"),
+                label: "Kickoff".to_string(),
+            },
+            Milestone {
+                date: date("2026-10-20"),
+                label: "Review".to_string(),
+            },
+        ];
+        let markdown = generate_markdown(&dates, &symbols(), &[], Locale::en_US);
+        assert!(markdown.ends_with("## Upcoming Milestones\n\n- 2026-10-20 Review (in 4 days)"));
+        assert!(!markdown.contains("Kickoff"));
+    }
+
+    #[test]
+    fn visible_width_counts_terminal_columns() {
+        assert_eq!(visible_width("Oct 05"), 6);
+        assert_eq!(visible_width("10月 05"), 7);
+        assert_eq!(visible_width(&paint("◼ ◻", UNDERLINE, true)), 3);
+    }
+
+    #[test]
+    fn wide_week_labels_are_measured_in_columns() {
+        let dates = calendar_dates("2026-10-01", "2026-10-20");
+        assert_eq!(week_label_width(Locale::ja_JP), 7);
+        let line =
+            generate_week_calendar(date("2026-10-12"), &dates, &symbols(), Locale::ja_JP, false);
+        assert_eq!(line, "10月 12 ◼ ◼ ◼ ◼ ◈ ◻ ◻");
+        assert_eq!(visible_width(&line), 21);
+    }
+
+    #[test]
+    fn parse_locale_ignores_encoding_and_modifier() {
+        assert_eq!(parse_locale("de_DE"), Ok(Locale::de_DE));
+        assert_eq!(parse_locale("de_DE.UTF-8"), Ok(Locale::de_DE));
+        assert_eq!(parse_locale("de_DE@euro"), Ok(Locale::de_DE));
+        assert!(parse_locale("xx_XX").is_err());
+    }
+
+    #[test]
+    fn german_locale_names_months_and_weekdays() {
+        let dates = calendar_dates("2026-10-01", "2026-11-10");
+        let boundaries = Boundaries {
+            months: true,
+            quarters: false,
+        };
+        let lines: Vec<_> =
+            generate_calendar(&dates, &symbols(), boundaries, Locale::de_DE, false).collect();
+        assert_eq!(lines[0], "-- Oktober 2026 --");
+        assert!(lines[1].starts_with("Sep 28 "));
+        assert!(lines.contains(&"-- November 2026 --".to_string()));
+
+        let markdown = generate_markdown(&dates, &symbols(), &[], Locale::de_DE);
+        assert!(markdown.contains("| Week | Mo | Di | Mi | Do | Fr | Sa | So |"));
+        assert!(markdown.contains("| Okt 05 |"));
+    }
+
+    #[test]
+    fn parse_date_accepts_iso_and_relative() {
+        assert_eq!(
+            parse_date("2026-01-02", today()).unwrap(),
+            date("2026-01-02")
+        );
+        assert_eq!(parse_date("tomorrow", today()).unwrap(), date("2026-10-17"));
+        assert!(parse_date("nope", today()).is_err());
+    }
+}